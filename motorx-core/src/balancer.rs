@@ -0,0 +1,724 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{HeaderMap, HeaderValue, Request};
+#[cfg(feature = "logging")]
+use itertools::Itertools;
+
+use crate::{
+    cfg_logging,
+    config::{
+        headers::HeaderVars, HashKey, LoadBalance, OutlierDetection, StickyCookie, Upstream,
+        UpstreamAddr, UpstreamServer,
+    },
+    conn_pool::{ConnPool, RequestQueue},
+    connector::Connector,
+    dns::Resolver,
+    handle::util::get_cookie,
+    health::{Circuit, Health},
+    retry::RetryBudget,
+    Upstreams,
+};
+
+/// Number of points each unit of weight gets on the consistent hash ring
+const RING_POINTS_PER_WEIGHT: u32 = 160;
+
+/// A single server of an upstream, with its own connection pool
+#[derive(Debug)]
+pub(crate) struct Backend {
+    pub(crate) addr: UpstreamAddr,
+    /// Address `addr`'s host resolved to, if the upstream re-resolves its hostnames
+    pub(crate) resolved: Option<IpAddr>,
+    /// Stable identifier derived from `addr`, used as the sticky cookie value
+    pub(crate) id: String,
+    /// `Host` header sent to this backend
+    pub(crate) host: HeaderValue,
+    pub(crate) weight: u32,
+    /// Only gets requests when no primary backend is usable
+    pub(crate) backup: bool,
+    pub(crate) pool: ConnPool,
+    pub(crate) health: Health,
+}
+
+/// Picks which of an upstream's servers a request goes to
+#[derive(Debug)]
+pub(crate) struct Balancer {
+    strategy: LoadBalance,
+    /// Current backends, replaced when hostnames resolve to different addresses
+    backends: RwLock<Arc<Tiers>>,
+    /// Position in the round robin cycle
+    next: AtomicUsize,
+    sticky: Option<StickyCookie>,
+    outlier_detection: Option<OutlierDetection>,
+    circuit: Option<Circuit>,
+    pub(crate) retry_budget: RetryBudget,
+    /// Requests waiting for a connection to any of the backends
+    pub(crate) queue: Arc<RequestQueue>,
+    connector: Arc<Connector>,
+    max_connections: usize,
+    /// Backends created on demand, if the upstream is dynamic
+    dynamic: Option<DynamicBackends>,
+}
+
+/// Backends of a dynamic upstream, by the address its template filled in to
+#[derive(Debug)]
+struct DynamicBackends {
+    upstream: Arc<Upstream>,
+    backends: Mutex<HashMap<String, DynamicBackend>>,
+}
+
+#[derive(Debug)]
+struct DynamicBackend {
+    backend: Arc<Backend>,
+    last_used: Instant,
+}
+
+/// Snapshot of an upstream's backends, split into primaries and backups
+#[derive(Debug)]
+struct Tiers {
+    all: Vec<Arc<Backend>>,
+    primary: Backends,
+    /// Used once no primary is usable
+    backup: Option<Backends>,
+}
+
+/// Backends requests are balanced across
+#[derive(Debug)]
+struct Backends {
+    list: Vec<Arc<Backend>>,
+    /// Running total of weights, used to do weighted picks over `list`
+    cumulative_weights: Vec<u64>,
+    /// Sorted (point, index into `list`) pairs, only populated for consistent hashing
+    ring: Vec<(u64, usize)>,
+}
+
+impl Backend {
+    fn new(
+        server: &UpstreamServer,
+        resolved: Option<IpAddr>,
+        upstream: &Upstream,
+        connector: &Arc<Connector>,
+        max_connections: usize,
+        queue: &Arc<RequestQueue>,
+    ) -> Result<Self, crate::Error> {
+        let mut endpoint = connector.endpoint(&server.addr, upstream)?;
+        let id = match resolved {
+            Some(ip) => {
+                endpoint = endpoint.resolved_to(ip);
+                hash(format!("{}@{ip}", server.addr).as_bytes())
+            }
+            None => hash(server.addr.to_string().as_bytes()),
+        };
+
+        Ok(Self {
+            pool: ConnPool::new(
+                server.addr.clone(),
+                Arc::clone(connector),
+                endpoint,
+                max_connections,
+                &upstream.pool,
+                Arc::clone(queue),
+            ),
+            id: format!("{id:016x}"),
+            host: server.addr.host(),
+            addr: server.addr.clone(),
+            resolved,
+            weight: server.weight,
+            backup: server.backup,
+            health: Health::new(),
+        })
+    }
+
+    /// Whether requests can be sent to this backend
+    #[inline]
+    pub(crate) fn is_available(&self) -> bool {
+        self.health.is_healthy() && !self.health.is_ejected()
+    }
+
+    /// Requests in flight relative to this backend's weight, lower is less loaded
+    #[inline]
+    fn is_less_loaded_than(&self, other: &Backend) -> bool {
+        // Cross multiply to compare `in_flight / weight` without floats
+        (self.pool.in_flight() as u64) * u64::from(other.weight)
+            < (other.pool.in_flight() as u64) * u64::from(self.weight)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.resolved {
+            Some(ip) => write!(f, "{} ({ip})", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl Tiers {
+    fn new(all: Vec<Arc<Backend>>, strategy: &LoadBalance) -> Self {
+        let (backup, primary) = all
+            .iter()
+            .cloned()
+            .partition::<Vec<_>, _>(|backend| backend.backup);
+
+        Self {
+            primary: Backends::new(primary, strategy),
+            backup: (!backup.is_empty()).then(|| Backends::new(backup, strategy)),
+            all,
+        }
+    }
+}
+
+impl Backends {
+    fn new(list: Vec<Arc<Backend>>, strategy: &LoadBalance) -> Self {
+        let cumulative_weights = list
+            .iter()
+            .scan(0, |total, backend| {
+                *total += u64::from(backend.weight);
+                Some(*total)
+            })
+            .collect();
+        let ring = if let LoadBalance::ConsistentHash(_) = strategy {
+            build_ring(&list)
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            list,
+            cumulative_weights,
+            ring,
+        }
+    }
+
+    #[inline]
+    fn total_weight(&self) -> u64 {
+        *self.cumulative_weights.last().unwrap()
+    }
+
+    #[inline]
+    fn random(&self) -> usize {
+        self.by_weight(fastrand::u64(..self.total_weight()))
+    }
+
+    /// Index of the backend `point` falls on, where each backend covers a range of `weight` points
+    #[inline]
+    fn by_weight(&self, point: u64) -> usize {
+        self.cumulative_weights.partition_point(|&w| w <= point)
+    }
+
+    /// First usable backend starting at `idx`, wrapping around
+    fn usable_from(
+        &self,
+        idx: usize,
+        usable: impl Fn(&Arc<Backend>) -> bool,
+    ) -> Option<&Arc<Backend>> {
+        let len = self.list.len();
+        (0..len)
+            .map(|i| &self.list[(idx + i) % len])
+            .find(|backend| usable(backend))
+    }
+}
+
+impl Balancer {
+    pub(crate) fn from_upstream(
+        upstream: &Arc<Upstream>,
+        resolver: &Resolver,
+    ) -> Result<Self, crate::Error> {
+        let connector = Arc::new(Connector::new(upstream, resolver)?);
+        let queue = Arc::new(RequestQueue::new(&upstream.pool));
+        let mut backends = Vec::new();
+
+        for server in upstream.all_servers() {
+            if server.weight == 0 {
+                return Err(crate::Error::Config(format!(
+                    "Weight of server {} must be greater than 0",
+                    server.addr
+                )));
+            }
+
+            backends.push(Arc::new(Backend::new(
+                &server,
+                None,
+                upstream,
+                &connector,
+                upstream.max_connections,
+                &queue,
+            )?));
+        }
+
+        let dynamic = upstream.dynamic.as_ref().map(|_| DynamicBackends {
+            upstream: Arc::clone(upstream),
+            backends: Mutex::new(HashMap::new()),
+        });
+        if dynamic.is_some() && !backends.is_empty() {
+            return Err(crate::Error::Config(
+                "Dynamic upstreams can't have an `addr` or `servers`".into(),
+            ));
+        }
+        if upstream
+            .dynamic
+            .as_ref()
+            .is_some_and(|dynamic| dynamic.max_servers == 0)
+        {
+            return Err(crate::Error::Config(
+                "`max_servers` of dynamic upstreams must be at least 1".into(),
+            ));
+        }
+
+        if backends.is_empty() && dynamic.is_none() {
+            return Err(crate::Error::Config(
+                "Upstreams must have an `addr` or at least one server in `servers`".into(),
+            ));
+        }
+        if !backends.is_empty() && backends.iter().all(|backend| backend.backup) {
+            return Err(crate::Error::Config(
+                "Upstreams must have at least one server which isn't a backup".into(),
+            ));
+        }
+
+        Ok(Self {
+            backends: RwLock::new(Arc::new(Tiers::new(backends, &upstream.load_balance))),
+            strategy: upstream.load_balance.clone(),
+            next: AtomicUsize::new(0),
+            sticky: upstream.sticky.clone(),
+            outlier_detection: upstream.outlier_detection.clone(),
+            circuit: upstream.circuit_breaker.clone().map(Circuit::new),
+            retry_budget: RetryBudget::new(),
+            queue,
+            connector,
+            max_connections: upstream.max_connections,
+            dynamic,
+        })
+    }
+
+    #[inline]
+    fn snapshot(&self) -> Arc<Tiers> {
+        Arc::clone(&self.backends.read().unwrap())
+    }
+
+    /// Replaces the backends of each hostname in `resolved` with one for each address it resolved to.
+    /// Backends for addresses which are still present are kept, along with their connections and health
+    pub(crate) fn set_resolved(
+        &self,
+        upstream: &Upstream,
+        resolved: &HashMap<String, Vec<IpAddr>>,
+    ) -> Result<(), crate::Error> {
+        let current = self.snapshot();
+        let mut list = Vec::new();
+
+        for server in upstream.all_servers() {
+            let existing = current
+                .all
+                .iter()
+                .filter(|backend| backend.addr == server.addr);
+            let ips = match &server.addr {
+                UpstreamAddr::Uri(uri) => uri.host().and_then(|host| resolved.get(host)),
+                UpstreamAddr::Unix(_) => None,
+            };
+
+            let Some(ips) = ips else {
+                // Not resolved this time, keep what it had
+                list.extend(existing.cloned());
+                continue;
+            };
+
+            for ip in ips {
+                match existing
+                    .clone()
+                    .find(|backend| backend.resolved == Some(*ip))
+                {
+                    Some(backend) => list.push(Arc::clone(backend)),
+                    None => list.push(Arc::new(Backend::new(
+                        &server,
+                        Some(*ip),
+                        upstream,
+                        &self.connector,
+                        self.max_connections,
+                        &self.queue,
+                    )?)),
+                }
+            }
+        }
+
+        let changed = list.len() != current.all.len()
+            || list
+                .iter()
+                .zip(&current.all)
+                .any(|(new, old)| !Arc::ptr_eq(new, old));
+
+        if changed {
+            cfg_logging! {
+                info!("Upstream servers changed to: {}", list.iter().map(ToString::to_string).join(", "));
+            }
+            *self.backends.write().unwrap() = Arc::new(Tiers::new(list, &self.strategy));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the circuit breaker lets requests through to this upstream's primary backends
+    fn circuit_allows(&self) -> bool {
+        self.circuit.as_ref().is_none_or(Circuit::allows)
+    }
+
+    /// Records the result of a request to `backend` for outlier detection and circuit breaking
+    pub(crate) fn record_outcome(&self, backend: &Backend, success: bool) {
+        // The circuit only covers primaries, backups are what it fails over to
+        if let Some(circuit) = self.circuit.as_ref().filter(|_| !backend.backup) {
+            circuit.record_outcome(success);
+        }
+
+        let Some(detection) = &self.outlier_detection else {
+            return;
+        };
+
+        let can_eject = !success && {
+            let backends = self.snapshot();
+            let ejected = backends
+                .all
+                .iter()
+                .filter(|backend| backend.health.is_ejected())
+                .count();
+            // One server can always be ejected, so small upstreams aren't left without detection
+            ejected == 0
+                || (ejected + 1) * 100
+                    <= backends.all.len() * detection.max_ejection_percent as usize
+        };
+
+        if let Some(_ejection_time) = backend.health.record_outcome(success, detection, can_eject) {
+            cfg_logging! {
+                warn!("Ejected upstream server {} for {:?} after failed requests", backend, _ejection_time);
+            }
+        }
+    }
+
+    /// Current backends of this upstream
+    pub(crate) fn backends(&self) -> Vec<Arc<Backend>> {
+        let mut backends = self.snapshot().all.clone();
+        if let Some(dynamic) = &self.dynamic {
+            let dynamic = dynamic.backends.lock().unwrap();
+            backends.extend(dynamic.values().map(|entry| Arc::clone(&entry.backend)));
+        }
+
+        backends
+    }
+
+    /// Backend of a dynamic upstream for the address its template fills in to for `req`,
+    /// it is created if it doesn't exist yet
+    fn dynamic_backend<B>(
+        &self,
+        dynamic: &DynamicBackends,
+        req: &Request<B>,
+    ) -> Option<Arc<Backend>> {
+        let settings = dynamic.upstream.dynamic.as_ref()?;
+        let vars = req.extensions().get::<HeaderVars>()?;
+        let addr = settings.addr.render_str(vars);
+
+        let mut backends = dynamic.backends.lock().unwrap();
+        if let Some(entry) = backends.get_mut(&addr) {
+            entry.last_used = Instant::now();
+            return Some(Arc::clone(&entry.backend));
+        }
+
+        let server = UpstreamServer {
+            addr: match addr.parse() {
+                Ok(addr) => addr,
+                Err(_err) => {
+                    cfg_logging! {warn!("Dynamic upstream address {addr:?} is invalid: {_err}");}
+                    return None;
+                }
+            },
+            weight: 1,
+            backup: false,
+        };
+        let backend = match Backend::new(
+            &server,
+            None,
+            &dynamic.upstream,
+            &self.connector,
+            self.max_connections,
+            &self.queue,
+        ) {
+            Ok(backend) => Arc::new(backend),
+            Err(_err) => {
+                cfg_logging! {warn!("Failed to create dynamic upstream server {addr}: {_err}");}
+                return None;
+            }
+        };
+
+        if backends.len() >= settings.max_servers {
+            let least_recent = backends
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(addr, _)| addr.clone());
+            if let Some(least_recent) = least_recent {
+                cfg_logging! {debug!("Dropping dynamic upstream server {least_recent} to make room");}
+                backends.remove(&least_recent);
+            }
+        }
+
+        cfg_logging! {info!("Created dynamic upstream server {addr}");}
+        backends.insert(
+            addr,
+            DynamicBackend {
+                backend: Arc::clone(&backend),
+                last_used: Instant::now(),
+            },
+        );
+
+        Some(backend)
+    }
+
+    /// Drops dynamic backends which haven't been used in `idle_timeout`
+    fn expire_dynamic(&self, idle_timeout: Duration) {
+        let Some(dynamic) = &self.dynamic else {
+            return;
+        };
+
+        dynamic.backends.lock().unwrap().retain(|_addr, entry| {
+            let keep = entry.last_used.elapsed() < idle_timeout;
+            if !keep {
+                cfg_logging! {debug!("Dropping idle dynamic upstream server {_addr}");}
+            }
+            keep
+        });
+    }
+
+    /// Choose the backend `req` should be sent to, `None` if no backend is available.
+    /// Backups are chosen from once no primary is usable or the circuit breaker is open
+    pub(crate) fn pick<B>(&self, req: &Request<B>, client_ip: IpAddr) -> Option<Arc<Backend>> {
+        self.pick_excluding(req, client_ip, &[])
+    }
+
+    /// Like [`Balancer::pick`], avoiding the `excluded` backends unless they are the only ones available
+    pub(crate) fn pick_excluding<B>(
+        &self,
+        req: &Request<B>,
+        client_ip: IpAddr,
+        excluded: &[Arc<Backend>],
+    ) -> Option<Arc<Backend>> {
+        if let Some(dynamic) = &self.dynamic {
+            // A request only has one server to go to, so retries go to it again
+            return self
+                .dynamic_backend(dynamic, req)
+                .filter(|backend| self.circuit_allows() && backend.is_available());
+        }
+
+        let tiers = self.snapshot();
+        let backends = if !self.circuit_allows() {
+            // Fail fast unless there are backups to fail over to
+            tiers.backup.as_ref()?
+        } else if tiers
+            .primary
+            .list
+            .iter()
+            .any(|backend| backend.is_available())
+        {
+            &tiers.primary
+        } else {
+            tiers.backup.as_ref().unwrap_or(&tiers.primary)
+        };
+        let usable = |backend: &Arc<Backend>| {
+            backend.is_available() && !excluded.iter().any(|e| Arc::ptr_eq(e, backend))
+        };
+
+        match self.pick_from(backends, req, client_ip, usable) {
+            Some(backend) => Some(Arc::clone(backend)),
+            None if !excluded.is_empty() => self
+                .pick_from(backends, req, client_ip, |backend| backend.is_available())
+                .map(Arc::clone),
+            None => None,
+        }
+    }
+
+    fn pick_from<'a, B>(
+        &self,
+        backends: &'a Backends,
+        req: &Request<B>,
+        client_ip: IpAddr,
+        usable: impl Fn(&Arc<Backend>) -> bool,
+    ) -> Option<&'a Arc<Backend>> {
+        if let Some(backend) = self
+            .sticky_backend(backends, req.headers())
+            .filter(|backend| usable(backend))
+        {
+            return Some(backend);
+        }
+
+        match &self.strategy {
+            LoadBalance::RoundRobin => {
+                let position = self.next.fetch_add(1, Ordering::Relaxed) as u64;
+                backends.usable_from(
+                    backends.by_weight(position % backends.total_weight()),
+                    usable,
+                )
+            }
+            LoadBalance::LeastConnections => {
+                // Start at a rotating offset so ties don't always go to the first backend
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                let len = backends.list.len();
+
+                (0..len)
+                    .map(|i| &backends.list[(offset + i) % len])
+                    .filter(|backend| usable(backend))
+                    .reduce(|least, backend| {
+                        if backend.is_less_loaded_than(least) {
+                            backend
+                        } else {
+                            least
+                        }
+                    })
+            }
+            LoadBalance::Random => backends.usable_from(backends.random(), usable),
+            LoadBalance::PowerOfTwoChoices => {
+                let first = backends.usable_from(backends.random(), &usable)?;
+                let second = backends.usable_from(backends.random(), &usable)?;
+
+                if second.is_less_loaded_than(first) {
+                    Some(second)
+                } else {
+                    Some(first)
+                }
+            }
+            LoadBalance::ConsistentHash(key) => {
+                let point = hash_key(key, req, client_ip);
+                let ring = &backends.ring;
+                // First point on the ring at or after the key's hash, wrapping to the start,
+                // walking further around the ring to skip unavailable backends
+                let start = ring.partition_point(|&(p, _)| p < point);
+
+                (0..ring.len())
+                    .map(|i| &backends.list[ring[(start + i) % ring.len()].1])
+                    .find(|backend| usable(backend))
+            }
+        }
+    }
+
+    /// Backend named by the request's sticky cookie, if it names one of ours
+    fn sticky_backend<'a>(
+        &self,
+        backends: &'a Backends,
+        headers: &HeaderMap,
+    ) -> Option<&'a Arc<Backend>> {
+        let sticky = self.sticky.as_ref()?;
+        let id = get_cookie(headers, &sticky.name)?;
+        backends
+            .list
+            .iter()
+            .find(|backend| backend.id == id && backend.is_available())
+    }
+
+    /// `Set-Cookie` value pinning the client to `backend`, if sticky sessions are enabled
+    /// and the client isn't pinned to it already
+    pub(crate) fn sticky_cookie(
+        &self,
+        req_headers: &HeaderMap,
+        backend: &Backend,
+    ) -> Option<HeaderValue> {
+        let sticky = self.sticky.as_ref()?;
+
+        if get_cookie(req_headers, &sticky.name) == Some(backend.id.as_str()) {
+            return None;
+        }
+
+        let mut cookie = format!(
+            "{}={}; Path={}; HttpOnly",
+            sticky.name, backend.id, sticky.path
+        );
+        if let Some(max_age) = sticky.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if sticky.secure {
+            cookie.push_str("; Secure");
+        }
+
+        HeaderValue::from_str(&cookie).ok()
+    }
+}
+
+fn build_ring(backends: &[Arc<Backend>]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::new();
+
+    for (idx, backend) in backends.iter().enumerate() {
+        for i in 0..backend.weight * RING_POINTS_PER_WEIGHT {
+            ring.push((hash(format!("{}-{i}", backend.id).as_bytes()), idx));
+        }
+    }
+
+    ring.sort_unstable();
+    ring
+}
+
+/// Spawns a task for each dynamic upstream which drops its idle servers,
+/// it stops once `upstreams` is dropped
+pub(crate) fn spawn_dynamic_expiry(upstreams: &Arc<Upstreams>) {
+    for (key, (upstream, _)) in upstreams.iter().enumerate() {
+        let Some(dynamic) = &upstream.dynamic else {
+            continue;
+        };
+        let idle_timeout = dynamic.idle_timeout;
+        let upstreams = Arc::downgrade(upstreams);
+
+        tokio::spawn(run_dynamic_expiry(upstreams, key, idle_timeout));
+    }
+}
+
+async fn run_dynamic_expiry(upstreams: Weak<Upstreams>, key: usize, idle_timeout: Duration) {
+    let mut interval = tokio::time::interval((idle_timeout / 2).max(Duration::from_millis(10)));
+
+    loop {
+        interval.tick().await;
+
+        let Some(upstreams) = upstreams.upgrade() else {
+            return;
+        };
+        upstreams[key].1.expire_dynamic(idle_timeout);
+    }
+}
+
+fn hash_key<B>(key: &HashKey, req: &Request<B>, client_ip: IpAddr) -> u64 {
+    let value = match key {
+        HashKey::ClientIp => None,
+        HashKey::Header(name) => req.headers().get(name).map(HeaderValue::as_bytes),
+        HashKey::Cookie(name) => get_cookie(req.headers(), name).map(str::as_bytes),
+        HashKey::PathSegment(idx) => req
+            .uri()
+            .path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .nth(*idx)
+            .map(str::as_bytes),
+    };
+
+    match value {
+        Some(value) => hash(value),
+        None => match client_ip {
+            IpAddr::V4(ip) => hash(&ip.octets()),
+            IpAddr::V6(ip) => hash(&ip.octets()),
+        },
+    }
+}
+
+/// FNV-1a with a final mix, stable across runs and platforms so keys map to the same servers after restarts
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    // Spread nearby inputs across the ring (murmur3 finalizer)
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
@@ -17,6 +17,8 @@ use crate::{
     handle::util,
 };
 
+pub(crate) type InflightSender = broadcast::Sender<Option<Arc<CloneableRes<Bytes>>>>;
+
 #[derive(Debug)]
 pub(crate) struct CloneableRes<T>(pub Response<T>);
 
@@ -56,7 +58,7 @@ pub(crate) struct CacheEntry {
     pub(crate) cached_at: Option<Instant>,
     // TODO: allow storing the data on disk as well as in memory
     pub(crate) value: Option<Response<Bytes>>,
-    pub(crate) inflight: Option<Weak<broadcast::Sender<Option<Arc<CloneableRes<Bytes>>>>>>,
+    pub(crate) inflight: Option<Weak<InflightSender>>,
 }
 
 impl Cache {
@@ -86,7 +88,7 @@ impl Cache {
         rule: &Rule,
         uri: &Uri,
         max_connections: usize,
-    ) -> Arc<broadcast::Sender<Option<Arc<CloneableRes<Bytes>>>>> {
+    ) -> Arc<InflightSender> {
         let sender = Arc::new(broadcast::channel(max_connections).0);
 
         self.cache
@@ -1,7 +1,7 @@
 use regex::Regex;
 
 #[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Authentication {
     #[cfg_attr(feature = "serde-config", serde(default))]
     pub exclude: Vec<PathWithWildCard>,
@@ -10,7 +10,7 @@ pub struct Authentication {
 
 #[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
 #[cfg_attr(feature = "serde-config", serde(rename_all = "snake_case"))]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AuthenticationSource {
     /// Authenticate with another registered upstream
     Upstream {
@@ -22,12 +22,26 @@ pub enum AuthenticationSource {
     Path(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum PathWithWildCard {
     Path(String),
     WithWildCard(Regex),
 }
 
+impl PartialEq for PathWithWildCard {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PathWithWildCard::Path(path), PathWithWildCard::Path(other_path)) => {
+                path == other_path
+            }
+            (PathWithWildCard::WithWildCard(re), PathWithWildCard::WithWildCard(other_re)) => {
+                re.as_str() == other_re.as_str()
+            }
+            _ => false,
+        }
+    }
+}
+
 impl PathWithWildCard {
     pub fn matches(&self, subject_path: &str) -> bool {
         match self {
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use http::HeaderValue;
+use regex::Regex;
+
+use super::{
+    authentication::Authentication, match_type::MatchType, CacheSettings, HeaderOps, Retry,
+    Rewrite, Rule, Timeouts,
+};
+
+/// Rules sharing a path prefix, match conditions and settings, which may be nested.
+///
+/// Groups are flattened into rules on startup, so their rules are ordered together with `rules`
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RuleGroup {
+    /// Prefix of the paths of this group's rules, nested groups add theirs after it
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub prefix: String,
+    /// Pattern the request's host must match, from the uri's authority (ex. http2's `:authority`) or `Host`
+    pub match_host: Option<MatchType>,
+    /// Headers requests must match, rules and nested groups can add to them or replace them
+    pub match_headers: Option<HashMap<String, MatchType>>,
+    /// Settings of this group's rules, rules and nested groups can override them
+    #[cfg_attr(feature = "serde-config", serde(flatten))]
+    pub settings: RuleSettings,
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub rules: Vec<GroupRule>,
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub groups: Vec<RuleGroup>,
+}
+
+/// A rule in a [`RuleGroup`]
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GroupRule {
+    /// Path after the group's prefix, the prefix itself if it isn't set.
+    /// `contains` and `regex` paths are matched after the prefix too
+    pub path: Option<MatchType>,
+    /// Headers requests must match, in addition to the group's
+    pub match_headers: Option<HashMap<String, MatchType>>,
+    #[cfg_attr(feature = "serde-config", serde(flatten))]
+    pub settings: RuleSettings,
+}
+
+/// Settings of a [`Rule`] which can be inherited from a group, see [`Rule`] for what they do.
+///
+/// Settings which aren't set are inherited, header changes are combined with the group's,
+/// with the `set` of the more specific one winning for the same header
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RuleSettings {
+    pub upstream: Option<String>,
+    pub remove_match: Option<bool>,
+    pub cache: Option<CacheSettings>,
+    pub retry: Option<Retry>,
+    pub timeouts: Option<Timeouts>,
+    pub authentication: Option<Authentication>,
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub request_headers: HeaderOps,
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub response_headers: HeaderOps,
+    pub preserve_host: Option<bool>,
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default, with = "super::headers::de_opt_header_value")
+    )]
+    pub host: Option<HeaderValue>,
+    pub rewrite: Option<Rewrite>,
+}
+
+impl RuleSettings {
+    /// These settings, with the ones which aren't set taken from `parent`
+    fn inherit(&self, parent: &RuleSettings) -> RuleSettings {
+        RuleSettings {
+            upstream: self.upstream.clone().or_else(|| parent.upstream.clone()),
+            remove_match: self.remove_match.or(parent.remove_match),
+            cache: self.cache.clone().or_else(|| parent.cache.clone()),
+            retry: self.retry.clone().or_else(|| parent.retry.clone()),
+            timeouts: self.timeouts.or(parent.timeouts),
+            authentication: self
+                .authentication
+                .clone()
+                .or_else(|| parent.authentication.clone()),
+            request_headers: combine(&parent.request_headers, &self.request_headers),
+            response_headers: combine(&parent.response_headers, &self.response_headers),
+            preserve_host: self.preserve_host.or(parent.preserve_host),
+            host: self.host.clone().or_else(|| parent.host.clone()),
+            rewrite: self.rewrite.clone().or_else(|| parent.rewrite.clone()),
+        }
+    }
+}
+
+/// Header changes of `parent` followed by those of `child`
+fn combine(parent: &HeaderOps, child: &HeaderOps) -> HeaderOps {
+    fn chain<T: Clone>(parent: &[T], child: &[T]) -> Vec<T> {
+        parent.iter().chain(child).cloned().collect()
+    }
+
+    HeaderOps {
+        set: chain(&parent.set, &child.set),
+        add: chain(&parent.add, &child.add),
+        remove: chain(&parent.remove, &child.remove),
+    }
+}
+
+/// What a group passes down to its rules and nested groups
+#[derive(Default)]
+struct Scope {
+    prefix: String,
+    match_headers: HashMap<String, MatchType>,
+    settings: RuleSettings,
+}
+
+/// Flattens `groups` into rules
+pub(crate) fn compile(groups: &[RuleGroup]) -> Result<Vec<Rule>, crate::Error> {
+    let mut rules = Vec::new();
+    for group in groups {
+        group.compile(&Scope::default(), &mut rules)?;
+    }
+
+    Ok(rules)
+}
+
+impl RuleGroup {
+    fn compile(&self, parent: &Scope, rules: &mut Vec<Rule>) -> Result<(), crate::Error> {
+        let mut match_headers = parent.match_headers.clone();
+        match_headers.extend(self.match_headers.clone().unwrap_or_default());
+        if let Some(host) = &self.match_host {
+            match_headers.insert("host".into(), host.clone());
+        }
+
+        let scope = Scope {
+            prefix: format!("{}{}", parent.prefix, self.prefix),
+            match_headers,
+            settings: self.settings.inherit(&parent.settings),
+        };
+
+        for rule in &self.rules {
+            rules.push(rule.compile(&scope)?);
+        }
+        for group in &self.groups {
+            group.compile(&scope, rules)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl GroupRule {
+    fn compile(&self, scope: &Scope) -> Result<Rule, crate::Error> {
+        let path = match &self.path {
+            Some(path) => scoped_path(&scope.prefix, path)?,
+            None => MatchType::Start(scope.prefix.clone()),
+        };
+
+        let mut match_headers = scope.match_headers.clone();
+        match_headers.extend(self.match_headers.clone().unwrap_or_default());
+
+        let settings = self.settings.inherit(&scope.settings);
+        let Some(upstream) = settings.upstream else {
+            return Err(crate::Error::Config(format!(
+                "The rule for {path} in a group has no `upstream`, it or one of its groups must set one"
+            )));
+        };
+
+        Ok(Rule {
+            path,
+            remove_match: settings.remove_match.unwrap_or_default(),
+            match_headers: (!match_headers.is_empty()).then_some(match_headers),
+            upstream,
+            cache: settings.cache,
+            retry: settings.retry,
+            timeouts: settings.timeouts,
+            authentication: settings.authentication,
+            request_headers: settings.request_headers,
+            response_headers: settings.response_headers,
+            preserve_host: settings.preserve_host,
+            host: settings.host,
+            rewrite: settings.rewrite.unwrap_or_default(),
+            cache_key: 0,
+            upstream_key: 0,
+            handler: None,
+        })
+    }
+}
+
+/// `path` matched after `prefix`, `contains` and `regex` paths become regexes starting with the prefix
+fn scoped_path(prefix: &str, path: &MatchType) -> Result<MatchType, crate::Error> {
+    if prefix.is_empty() {
+        return Ok(path.clone());
+    }
+
+    let escaped = regex::escape(prefix);
+    let pattern = match path {
+        MatchType::Start(start) => return Ok(MatchType::Start(format!("{prefix}{start}"))),
+        MatchType::Contains(contained) => format!("^{escaped}.*{}", regex::escape(contained)),
+        MatchType::Regex(re) => match re.as_str().strip_prefix('^') {
+            // Anchored to the start of the path, so now to the end of the prefix
+            Some(anchored) => format!("^{escaped}(?:{anchored})"),
+            None => format!("^{escaped}.*?(?:{})", re.as_str()),
+        },
+    };
+
+    Regex::new(&pattern).map(MatchType::Regex).map_err(|e| {
+        crate::Error::Config(format!(
+            "Failed to add group prefix {prefix:?} to {path}: {e}"
+        ))
+    })
+}
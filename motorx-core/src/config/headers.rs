@@ -0,0 +1,347 @@
+use std::{collections::HashMap, fmt, net::IpAddr, str::FromStr};
+
+use http::{header::HOST, HeaderMap, HeaderName, HeaderValue, Request};
+
+use regex::{Captures, Regex};
+
+use super::{match_type::MatchType, rule::request_host, Rule};
+use crate::request_id::RequestId;
+
+/// Changes made to the headers of a request or response, `remove` is applied first, then `set`, then `add`.
+///
+/// Values may contain variables:
+///   - `${client_ip}`: address of the client
+///   - `${host}`: host the client sent the request to
+///   - `${request_id}`: the request's id, see `request_id_header`
+///   - `${1}`, `${name}`: groups captured by a rule's `regex(...)` path,
+///     named groups can also come from its `regex(...)` in `match_headers`
+///
+/// Variables without a value are replaced with nothing.
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HeaderOps {
+    /// Headers to replace, or insert if they are missing
+    #[cfg_attr(feature = "serde-config", serde(default, with = "de_header_templates"))]
+    pub set: Vec<(HeaderName, Template)>,
+    /// Headers to insert, keeping any values already present
+    #[cfg_attr(feature = "serde-config", serde(default, with = "de_header_templates"))]
+    pub add: Vec<(HeaderName, Template)>,
+    /// Headers to remove
+    #[cfg_attr(feature = "serde-config", serde(default, with = "de_header_names"))]
+    pub remove: Vec<HeaderName>,
+}
+
+impl HeaderOps {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.add.is_empty() && self.remove.is_empty()
+    }
+
+    /// Errors if these changes touch `Host`, requests get theirs from `host` and `preserve_host`
+    /// once the server is picked, which would replace it
+    pub(crate) fn check_request(&self, owner: fmt::Arguments) -> Result<(), crate::Error> {
+        let mut names = self.set.iter().chain(&self.add).map(|(name, _)| name);
+        if names.any(|name| name == HOST) || self.remove.contains(&HOST) {
+            return Err(crate::Error::Config(format!(
+                "{owner} changes the `host` request header, use `host` or `preserve_host` instead"
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn apply(&self, headers: &mut HeaderMap, vars: &HeaderVars) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+
+        for (name, template) in &self.set {
+            if let Some(value) = template.render(vars) {
+                headers.insert(name.clone(), value);
+            }
+        }
+
+        for (name, template) in &self.add {
+            if let Some(value) = template.render(vars) {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+}
+
+/// A header value, with variables filled in for each request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    ClientIp,
+    Host,
+    RequestId,
+    Capture(usize),
+    NamedCapture(String),
+}
+
+impl Template {
+    /// Value of the header, `None` if a variable made it an invalid header value
+    fn render(&self, vars: &HeaderVars) -> Option<HeaderValue> {
+        HeaderValue::from_str(&self.render_str(vars)).ok()
+    }
+
+    /// Whether every value starts with `prefix`, ignoring case
+    pub(crate) fn starts_with(&self, prefix: &str) -> bool {
+        matches!(self.0.first(), Some(Part::Text(text))
+            if text.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix)))
+    }
+
+    /// The template with the request's variables filled in
+    pub(crate) fn render_str(&self, vars: &HeaderVars) -> String {
+        let mut value = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => value.push_str(text),
+                Part::ClientIp => value.push_str(&vars.client_ip.to_string()),
+                Part::Host => value.push_str(vars.host.as_deref().unwrap_or_default()),
+                Part::RequestId => value.push_str(vars.request_id.as_deref().unwrap_or_default()),
+                Part::Capture(i) => {
+                    if let Some(Some(capture)) = vars.captures.get(*i) {
+                        value.push_str(capture);
+                    }
+                }
+                Part::NamedCapture(name) => {
+                    if let Some(capture) = vars.named_captures.get(name) {
+                        value.push_str(capture);
+                    }
+                }
+            }
+        }
+
+        value
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateFromStrError(String);
+
+impl fmt::Display for TemplateFromStrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl std::error::Error for TemplateFromStrError {}
+
+impl FromStr for Template {
+    type Err = TemplateFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("${") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+
+            let Some(len) = rest[start + 2..].find('}') else {
+                return Err(TemplateFromStrError(format!(
+                    "Unclosed variable in header value {s:?}"
+                )));
+            };
+            let name = &rest[start + 2..start + 2 + len];
+            parts.push(match name {
+                "client_ip" => Part::ClientIp,
+                "host" => Part::Host,
+                "request_id" => Part::RequestId,
+                "" => {
+                    return Err(TemplateFromStrError(format!(
+                        "Empty variable in header value {s:?}"
+                    )))
+                }
+                name => match name.parse() {
+                    Ok(i) => Part::Capture(i),
+                    Err(_) => Part::NamedCapture(name.to_string()),
+                },
+            });
+
+            rest = &rest[start + 2 + len + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        Ok(Template(parts))
+    }
+}
+
+/// Values of the variables available to templates for one request, stored in the request's extensions
+/// so dynamic upstreams can pick a server from them
+#[derive(Debug, Clone)]
+pub(crate) struct HeaderVars {
+    client_ip: IpAddr,
+    host: Option<String>,
+    request_id: Option<String>,
+    captures: Vec<Option<String>>,
+    named_captures: HashMap<String, String>,
+}
+
+impl HeaderVars {
+    pub(crate) fn new<B>(req: &Request<B>, client_ip: IpAddr, rule: &Rule) -> Self {
+        let mut captures = Vec::new();
+        let mut named_captures = HashMap::new();
+        if let MatchType::Regex(re) = &rule.path {
+            if let Some(caps) = re.captures(req.uri().path()) {
+                captures = caps
+                    .iter()
+                    .map(|cap| cap.map(|cap| cap.as_str().to_string()))
+                    .collect();
+                named_captures.extend(named(re, &caps));
+            }
+        }
+
+        for (name, pattern) in rule.match_headers.iter().flatten() {
+            let MatchType::Regex(re) = pattern else {
+                continue;
+            };
+            let value = if name.eq_ignore_ascii_case(HOST.as_str()) {
+                request_host(req)
+            } else {
+                req.headers().get(name).and_then(|v| v.to_str().ok())
+            };
+            if let Some(caps) = value.and_then(|value| re.captures(value)) {
+                named_captures.extend(named(re, &caps));
+            }
+        }
+
+        Self {
+            client_ip,
+            host: request_host(req).map(str::to_string),
+            request_id: req
+                .extensions()
+                .get::<RequestId>()
+                .and_then(|id| id.0.to_str().ok())
+                .map(str::to_string),
+            captures,
+            named_captures,
+        }
+    }
+}
+
+/// Named groups `re` captured
+fn named<'a>(re: &'a Regex, caps: &'a Captures) -> impl Iterator<Item = (String, String)> + 'a {
+    re.capture_names()
+        .flatten()
+        .filter_map(|name| Some((name.to_string(), caps.name(name)?.as_str().to_string())))
+}
+
+#[cfg(feature = "serde-config")]
+mod de_template {
+    use serde::de::{Deserialize, Visitor};
+
+    use super::Template;
+
+    struct TemplateVisitor;
+
+    impl Visitor<'_> for TemplateVisitor {
+        type Value = Template;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                formatter,
+                "A header value, optionally with variables (ex. ${{client_ip}})"
+            )
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            v.parse()
+                .map_err(|e: super::TemplateFromStrError| E::custom(e))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Template {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_str(TemplateVisitor)
+        }
+    }
+}
+
+#[cfg(feature = "serde-config")]
+mod de_header_templates {
+    use std::collections::HashMap;
+
+    use http::HeaderName;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    use super::Template;
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Vec<(HeaderName, Template)>, D::Error> {
+        HashMap::<String, Template>::deserialize(de)?
+            .into_iter()
+            .map(|(name, template)| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map(|name| (name, template))
+                    .map_err(|_| D::Error::custom(format!("Invalid header name: {name:?}")))
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "serde-config")]
+mod de_header_names {
+    use http::HeaderName;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Vec<HeaderName>, D::Error> {
+        Vec::<String>::deserialize(de)?
+            .into_iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| D::Error::custom(format!("Invalid header name: {name:?}")))
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "serde-config")]
+pub(super) mod de_opt_header_value {
+    use http::HeaderValue;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub(in crate::config) fn deserialize<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Option<HeaderValue>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|value| {
+                HeaderValue::from_str(&value)
+                    .map_err(|_| D::Error::custom(format!("Invalid header value: {value:?}")))
+            })
+            .transpose()
+    }
+}
+
+#[cfg(feature = "serde-config")]
+pub(super) mod de_opt_header_name {
+    use http::HeaderName;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub(in crate::config) fn deserialize<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Option<HeaderName>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| D::Error::custom(format!("Invalid header name: {name:?}")))
+            })
+            .transpose()
+    }
+}
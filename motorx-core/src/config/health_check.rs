@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+/// Periodically sends a request to each of an upstream's servers,
+/// taking servers out of rotation while they fail
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Path the check's `GET` request is sent to
+    #[cfg_attr(feature = "serde-config", serde(default = "default_health_check_path"))]
+    pub path: String,
+    /// Status a healthy server responds with, any 2xx status if not set
+    pub expected_status: Option<u16>,
+    /// Time between checks
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_health_check_interval")
+    )]
+    pub interval: Duration,
+    /// How long a check can take before it counts as failed
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_health_check_timeout")
+    )]
+    pub timeout: Duration,
+    /// Consecutive passed checks for an unhealthy server to be put back in rotation
+    #[cfg_attr(feature = "serde-config", serde(default = "default_health_check_rise"))]
+    pub rise: u32,
+    /// Consecutive failed checks for a healthy server to be taken out of rotation
+    #[cfg_attr(feature = "serde-config", serde(default = "default_health_check_fall"))]
+    pub fall: u32,
+}
+
+fn default_health_check_path() -> String {
+    "/".into()
+}
+
+const fn default_health_check_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_health_check_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+const fn default_health_check_rise() -> u32 {
+    2
+}
+
+const fn default_health_check_fall() -> u32 {
+    3
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: default_health_check_path(),
+            expected_status: None,
+            interval: default_health_check_interval(),
+            timeout: default_health_check_timeout(),
+            rise: default_health_check_rise(),
+            fall: default_health_check_fall(),
+        }
+    }
+}
+
+/// Ejects servers from rotation based on the responses to real requests.
+/// Connection errors and 5xx responses count as failures
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutlierDetection {
+    /// Consecutive failures after which a server is ejected
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_outlier_consecutive_failures")
+    )]
+    pub consecutive_failures: Option<u32>,
+    /// Eject a server when too many of its requests fail within `window`
+    pub error_rate: Option<ErrorRate>,
+    /// Length of the window `error_rate` is measured over
+    #[cfg_attr(feature = "serde-config", serde(default = "default_outlier_window"))]
+    pub window: Duration,
+    /// How long a server is ejected for the first time,
+    /// each ejection in a row adds this much time, up to `max_ejection_time`
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_outlier_base_ejection_time")
+    )]
+    pub base_ejection_time: Duration,
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_outlier_max_ejection_time")
+    )]
+    pub max_ejection_time: Duration,
+    /// Most servers, as a percent of the upstream's servers, which can be ejected at once.
+    /// One server can always be ejected
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_outlier_max_ejection_percent")
+    )]
+    pub max_ejection_percent: u32,
+}
+
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorRate {
+    /// Percent of failed requests at which a server is ejected
+    pub percent: u32,
+    /// Requests needed in the window before the error rate is considered
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_error_rate_min_requests")
+    )]
+    pub min_requests: u32,
+}
+
+/// Fails requests to an upstream with 503 while its servers keep failing,
+/// instead of waiting on a broken pool
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// Consecutive failures across the upstream which open the circuit
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_circuit_failure_threshold")
+    )]
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial request is let through
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_circuit_open_duration")
+    )]
+    pub open_duration: Duration,
+}
+
+const fn default_outlier_consecutive_failures() -> Option<u32> {
+    Some(5)
+}
+
+const fn default_outlier_window() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_outlier_base_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_outlier_max_ejection_time() -> Duration {
+    Duration::from_secs(300)
+}
+
+const fn default_outlier_max_ejection_percent() -> u32 {
+    50
+}
+
+const fn default_error_rate_min_requests() -> u32 {
+    10
+}
+
+const fn default_circuit_failure_threshold() -> u32 {
+    5
+}
+
+const fn default_circuit_open_duration() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: default_outlier_consecutive_failures(),
+            error_rate: None,
+            window: default_outlier_window(),
+            base_ejection_time: default_outlier_base_ejection_time(),
+            max_ejection_time: default_outlier_max_ejection_time(),
+            max_ejection_percent: default_outlier_max_ejection_percent(),
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_circuit_failure_threshold(),
+            open_duration: default_circuit_open_duration(),
+        }
+    }
+}
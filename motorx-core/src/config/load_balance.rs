@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+/// How requests are spread across the servers of an upstream
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde-config", serde(rename_all = "snake_case"))]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum LoadBalance {
+    /// Cycle through servers in order, visiting each proportionally to its weight, default behavior
+    #[default]
+    RoundRobin,
+    /// Pick the server with the least requests in flight relative to its weight
+    LeastConnections,
+    /// Pick a random server, weighted
+    Random,
+    /// Pick two random servers (weighted) and use the one with less requests in flight
+    PowerOfTwoChoices,
+    /// Hash a key from the request onto a consistent hash ring of the servers,
+    /// so requests with the same key keep going to the same server
+    ConsistentHash(HashKey),
+}
+
+/// Part of a request used as the key for [`LoadBalance::ConsistentHash`]
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde-config", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashKey {
+    /// IP address of the client
+    ClientIp,
+    /// Value of a header, falls back to the client IP if the header is missing
+    Header(String),
+    /// Value of a cookie, falls back to the client IP if the cookie is missing
+    Cookie(String),
+    /// Segment of the path sent to the upstream, starting at 0 (ex. 1 is `b` in `/a/b/c`),
+    /// falls back to the client IP if the path is too short
+    PathSegment(usize),
+}
+
+/// Pins a client to a server by setting a cookie naming the server it was sent to
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StickyCookie {
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_sticky_cookie_name")
+    )]
+    pub name: String,
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_sticky_cookie_path")
+    )]
+    pub path: String,
+    /// How long the client should keep the cookie, it lasts for the browser session if not set
+    pub max_age: Option<Duration>,
+    /// Only send the cookie over https
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub secure: bool,
+}
+
+fn default_sticky_cookie_name() -> String {
+    "motorx_affinity".into()
+}
+
+fn default_sticky_cookie_path() -> String {
+    "/".into()
+}
+
+impl Default for StickyCookie {
+    fn default() -> Self {
+        Self {
+            name: default_sticky_cookie_name(),
+            path: default_sticky_cookie_path(),
+            max_age: None,
+            secure: false,
+        }
+    }
+}
@@ -1,45 +1,304 @@
 pub mod authentication;
+pub mod group;
+pub mod headers;
+pub mod health_check;
+pub mod load_balance;
 pub mod match_type;
+pub mod retry;
+pub mod rewrite;
 pub mod rule;
+pub mod timeouts;
+pub mod upstream_addr;
 
+pub use group::{GroupRule, RuleGroup, RuleSettings};
+pub use headers::{HeaderOps, Template};
+pub use health_check::{CircuitBreaker, HealthCheck, OutlierDetection};
+pub use load_balance::{HashKey, LoadBalance, StickyCookie};
+pub use retry::{Retry, RetryOn};
+pub use rewrite::Rewrite;
 pub use rule::{CacheSettings, Rule};
+pub use timeouts::Timeouts;
+pub use upstream_addr::UpstreamAddr;
 
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use http::Uri;
+use http::{HeaderName, HeaderValue};
+use ipnet::IpNet;
 
 use self::authentication::Authentication;
+use crate::handler::Handler;
 
 #[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
 #[derive(Debug)]
 pub struct Config {
     pub addr: SocketAddr,
     pub tls: Option<Tls>,
+    #[cfg_attr(feature = "serde-config", serde(default))]
     pub rules: Vec<Rule>,
+    /// Groups of rules sharing a prefix and settings, they are added to `rules` on startup
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub groups: Vec<RuleGroup>,
     pub upstreams: HashMap<String, Arc<Upstream>>,
+    /// Handlers serving requests in process, rules send requests to them by setting `upstream` to their name
+    #[cfg_attr(feature = "serde-config", serde(skip))]
+    pub handlers: HashMap<String, Handler>,
     #[cfg_attr(
         feature = "serde-config",
         serde(default = "default_server_max_connections")
     )]
     pub max_connections: usize,
+    /// Addresses to use for upstream hostnames instead of resolving them with DNS
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    /// Proxies in front of motorx (ex. `10.0.0.0/8` or `192.168.1.2`) whose forwarding headers are used
+    /// to find the client's ip, these headers are removed from requests sent by anyone else
+    #[cfg_attr(feature = "serde-config", serde(default, with = "de_ip_nets"))]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Header holding each request's id, the client's id is kept if it sent one, otherwise one is generated.
+    /// The id is sent to upstreams and returned to the client, `null` disables request ids
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(
+            default = "default_request_id_header",
+            with = "headers::de_opt_header_name"
+        )
+    )]
+    pub request_id_header: Option<HeaderName>,
+    /// Name this proxy adds to `Via` headers, requests which already passed through it are rejected
+    /// with 508 Loop Detected. It should be unique among the proxies requests go through,
+    /// a random name is used if it isn't set
+    #[cfg_attr(feature = "serde-config", serde(default = "default_proxy_name"))]
+    pub proxy_name: String,
 }
 
 #[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
 #[derive(Debug)]
 pub struct Upstream {
-    #[cfg_attr(feature = "serde-config", serde(with = "http_serde::uri"))]
-    pub addr: Uri,
+    /// Address of the upstream server, shorthand for a single server with a weight of 1
+    pub addr: Option<UpstreamAddr>,
+    /// Servers to balance requests across, in addition to `addr`
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub servers: Vec<UpstreamServer>,
+    /// How requests are spread across this upstream's servers
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub load_balance: LoadBalance,
+    /// Keep clients on the server they were first sent to with a cookie set by motorx,
+    /// `load_balance` is used for clients without the cookie
+    pub sticky: Option<StickyCookie>,
+    /// Maximum number of connections to each of this upstream's servers
     #[cfg_attr(
         feature = "serde-config",
         serde(default = "default_upstream_max_connections")
     )]
     pub max_connections: usize,
+    /// How long connections to this upstream's servers are kept, how many are kept ready,
+    /// and limits on requests waiting for them
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub pool: Pool,
     pub authentication: Option<Authentication>,
+    /// Actively check the health of this upstream's servers
+    pub health_check: Option<HealthCheck>,
+    /// Eject servers based on the responses to real requests
+    pub outlier_detection: Option<OutlierDetection>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Periodically resolve the hostnames of this upstream's servers,
+    /// balancing across every address they resolve to
+    pub resolve: Option<Resolve>,
+    /// Pick the server for each request from a template filled from the request, instead of `addr` and `servers`
+    pub dynamic: Option<Dynamic>,
+    /// Settings for connecting to this upstream's servers over tls,
+    /// tls is always used for `https` servers, setting this enables it for other servers
+    pub tls: Option<UpstreamTls>,
+    /// Http version used to talk to this upstream's servers
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub protocol: UpstreamProtocol,
+    /// Send a PROXY protocol header with the client's address at the start of each connection.
+    /// The client is the one found through `trusted_proxies`, and connections are only reused for
+    /// requests from the same client. `pool.min_idle` doesn't apply, since a connection opened
+    /// ahead of time can't name its client
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Send the `Host` the client used, instead of the authority of the server a request is sent to
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub preserve_host: bool,
+    /// `Host` sent with every request to this upstream, takes priority over `preserve_host`
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default, with = "headers::de_opt_header_value")
+    )]
+    pub host: Option<HeaderValue>,
+    /// Limits on how long requests to this upstream may take, rules can override each of them
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub timeouts: Timeouts,
+    /// Changes made to requests sent to this upstream, before those of the request's rule.
+    /// They can't change `Host` (see `host`)
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub request_headers: HeaderOps,
+    /// Changes made to responses from this upstream, before those of the request's rule
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub response_headers: HeaderOps,
     /// Upstreams key in a slab, it is overridden on startup
     #[cfg_attr(feature = "serde-config", serde(default))]
     pub key: usize,
 }
 
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone)]
+pub struct UpstreamServer {
+    pub addr: UpstreamAddr,
+    /// Share of requests this server gets relative to the others
+    #[cfg_attr(feature = "serde-config", serde(default = "default_server_weight"))]
+    pub weight: u32,
+    /// Only send requests to this server when every other server is unhealthy,
+    /// or the upstream's circuit breaker is open (ex. a disaster recovery site)
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub backup: bool,
+}
+
+/// Servers created for each address a template fills in to, ex. `http://pr-${pr}.internal:8080`
+/// for preview environments. A server's connections are opened when the first request to it comes in.
+///
+/// The template has the same variables as header values, see [`HeaderOps`].
+/// Captures used in it should be strict (ex. `(?P<pr>\d+)`), since they come from the client
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dynamic {
+    /// Address of the server a request is sent to. Tls is set up if it starts with `https://`
+    /// or the upstream sets `tls`, servers which need it otherwise can't be connected to
+    pub addr: Template,
+    /// Most servers kept at once, the least recently used is dropped to make room for a new one
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_dynamic_max_servers")
+    )]
+    pub max_servers: usize,
+    /// Drop servers which haven't gotten a request in this long, along with their connections
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_dynamic_idle_timeout")
+    )]
+    pub idle_timeout: Duration,
+}
+
+const fn default_dynamic_max_servers() -> usize {
+    100
+}
+
+const fn default_dynamic_idle_timeout() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+impl Dynamic {
+    pub fn new(addr: Template) -> Self {
+        Self {
+            addr,
+            max_servers: default_dynamic_max_servers(),
+            idle_timeout: default_dynamic_idle_timeout(),
+        }
+    }
+}
+
+/// Re-resolves hostnames on an interval, each address a hostname resolves to is used as a server
+/// with the weight of the hostname's server
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolve {
+    /// Time between resolving hostnames
+    #[cfg_attr(feature = "serde-config", serde(default = "default_resolve_interval"))]
+    pub interval: Duration,
+}
+
+const fn default_resolve_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl Default for Resolve {
+    fn default() -> Self {
+        Self {
+            interval: default_resolve_interval(),
+        }
+    }
+}
+
+/// Lifecycle of the connections kept to each of an upstream's servers, and limits on requests waiting for them
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pool {
+    /// Close connections which went unused this long, `null` keeps them until they fail
+    #[cfg_attr(feature = "serde-config", serde(default = "default_pool_idle_timeout"))]
+    pub idle_timeout: Option<Duration>,
+    /// Close connections once they are this old, requests using them are finished first
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub max_lifetime: Option<Duration>,
+    /// Idle connections kept open to each server, they are opened on startup
+    /// so the first requests don't wait on connecting
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub min_idle: usize,
+    /// Time a connection is idle before TCP keepalive probes are sent, `null` disables keepalive
+    #[cfg_attr(feature = "serde-config", serde(default = "default_pool_keepalive"))]
+    pub keepalive: Option<Duration>,
+    /// Requests allowed to wait for a connection across the upstream's servers once every connection is busy,
+    /// requests past it are rejected with 503
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub max_queued: Option<usize>,
+    /// Longest a request waits for a connection before it is rejected with 503
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub max_queue_wait: Option<Duration>,
+}
+
+const fn default_pool_idle_timeout() -> Option<Duration> {
+    Some(Duration::from_secs(90))
+}
+
+const fn default_pool_keepalive() -> Option<Duration> {
+    Some(Duration::from_secs(60))
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self {
+            idle_timeout: default_pool_idle_timeout(),
+            max_lifetime: None,
+            min_idle: 0,
+            keepalive: default_pool_keepalive(),
+            max_queued: None,
+            max_queue_wait: None,
+        }
+    }
+}
+
+/// Http version used to talk to an upstream's servers
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde-config", serde(rename_all = "snake_case"))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    /// Http/1.1, one request at a time per connection, default behavior
+    #[default]
+    Http1,
+    /// Http/2 with prior knowledge, h2c for servers not using tls.
+    /// Requests share connections, but upgrades (ex. websockets) aren't supported
+    Http2,
+    /// Negotiate the version with ALPN for tls servers, falls back to http/1.1 without tls
+    Auto,
+}
+
+/// Version of the PROXY protocol header sent to an upstream's servers
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde-config", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// Human readable header (ex. `PROXY TCP4 203.0.113.7 10.0.0.1 51234 443`)
+    V1,
+    /// Binary header
+    V2,
+}
+
 #[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
 #[derive(Debug)]
 pub enum Tls {
@@ -55,6 +314,45 @@ pub enum Tls {
     },
 }
 
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone)]
+pub struct UpstreamTls {
+    /// PEM file of certificate authorities to trust, in addition to the system's if `system_roots` is enabled
+    pub ca_file: Option<PathBuf>,
+    /// Trust the system's certificate authorities
+    #[cfg_attr(feature = "serde-config", serde(default = "default_true"))]
+    pub system_roots: bool,
+    /// Server name sent with SNI, the server's host by default
+    pub sni: Option<String>,
+    /// Name the server's certificate is verified against, `sni` by default
+    pub verify_hostname: Option<String>,
+    /// Accept any certificate from the server. Only use this for development!
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub insecure_skip_verify: bool,
+    /// PEM certificate chain presented to servers asking for client authentication (mTLS)
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key for `client_cert`
+    pub client_key: Option<PathBuf>,
+}
+
+impl Default for UpstreamTls {
+    fn default() -> Self {
+        Self {
+            ca_file: None,
+            system_roots: default_true(),
+            sni: None,
+            verify_hostname: None,
+            insecure_skip_verify: false,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+const fn default_true() -> bool {
+    true
+}
+
 const fn default_upstream_max_connections() -> usize {
     10
 }
@@ -63,6 +361,60 @@ const fn default_server_max_connections() -> usize {
     100
 }
 
+fn default_proxy_name() -> String {
+    format!("motorx-{:08x}", fastrand::u32(..))
+}
+
+const fn default_request_id_header() -> Option<HeaderName> {
+    Some(HeaderName::from_static("x-request-id"))
+}
+
+const fn default_server_weight() -> u32 {
+    1
+}
+
+impl Upstream {
+    /// All servers of this upstream, with `addr` first if present
+    pub fn all_servers(&self) -> impl Iterator<Item = UpstreamServer> + '_ {
+        self.addr
+            .iter()
+            .map(|addr| UpstreamServer {
+                addr: addr.clone(),
+                weight: default_server_weight(),
+                backup: false,
+            })
+            .chain(self.servers.iter().cloned())
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            servers: Vec::new(),
+            load_balance: Default::default(),
+            sticky: None,
+            max_connections: default_upstream_max_connections(),
+            pool: Default::default(),
+            authentication: None,
+            health_check: None,
+            outlier_detection: None,
+            circuit_breaker: None,
+            resolve: None,
+            dynamic: None,
+            tls: None,
+            protocol: Default::default(),
+            proxy_protocol: None,
+            preserve_host: false,
+            host: None,
+            timeouts: Default::default(),
+            request_headers: Default::default(),
+            response_headers: Default::default(),
+            key: 0,
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -73,7 +425,13 @@ impl Default for Config {
             tls: Default::default(),
             max_connections: default_server_max_connections(),
             rules: Vec::new(),
+            groups: Vec::new(),
             upstreams: HashMap::new(),
+            handlers: HashMap::new(),
+            hosts: HashMap::new(),
+            trusted_proxies: Vec::new(),
+            request_id_header: default_request_id_header(),
+            proxy_name: default_proxy_name(),
         }
     }
 }
@@ -86,3 +444,23 @@ impl FromStr for Config {
         serde_json::from_str(s)
     }
 }
+
+#[cfg(feature = "serde-config")]
+mod de_ip_nets {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    /// Networks in cidr notation, a lone ip is a network of just that ip
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<IpNet>, D::Error> {
+        Vec::<String>::deserialize(de)?
+            .into_iter()
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| D::Error::custom(format!("Invalid ip or cidr: {net:?}")))
+            })
+            .collect()
+    }
+}
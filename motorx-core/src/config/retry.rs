@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+/// When and how failed requests are sent again, each retry goes to a different server if there is one
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retry {
+    /// Times a request is tried in total, including the first
+    #[cfg_attr(feature = "serde-config", serde(default = "default_retry_attempts"))]
+    pub attempts: u32,
+    /// Failures which are retried
+    #[cfg_attr(feature = "serde-config", serde(default = "default_retry_on"))]
+    pub on: Vec<RetryOn>,
+    /// Retry methods which aren't idempotent (ex. `POST`) after the request reached the server.
+    /// Failing to connect is always retried, since the server never saw the request
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub non_idempotent: bool,
+    /// Delay before the first retry, doubling for each retry after, with random jitter
+    #[cfg_attr(feature = "serde-config", serde(default = "default_retry_backoff"))]
+    pub backoff: Duration,
+    /// Longest delay between retries
+    #[cfg_attr(feature = "serde-config", serde(default = "default_retry_max_backoff"))]
+    pub max_backoff: Duration,
+    /// Retries allowed as a percent of requests to the upstream each second,
+    /// stops retries from piling onto an upstream which is already failing
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_retry_budget_percent")
+    )]
+    pub budget_percent: u32,
+    /// Retries allowed each second no matter the budget, so upstreams with few requests can still retry
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default = "default_retry_min_per_second")
+    )]
+    pub min_retries_per_second: u32,
+}
+
+/// Failure of an attempt to proxy a request
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde-config", serde(rename_all = "snake_case"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOn {
+    /// Couldn't connect to the server
+    Connect,
+    /// Connection closed before the server responded
+    Reset,
+    /// Server didn't respond within the `response` timeout
+    Timeout,
+    /// Server responded with 502
+    BadGateway,
+    /// Server responded with 503
+    ServiceUnavailable,
+    /// Server responded with 504
+    GatewayTimeout,
+}
+
+const fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![
+        RetryOn::Connect,
+        RetryOn::Reset,
+        RetryOn::Timeout,
+        RetryOn::BadGateway,
+        RetryOn::ServiceUnavailable,
+        RetryOn::GatewayTimeout,
+    ]
+}
+
+const fn default_retry_backoff() -> Duration {
+    Duration::from_millis(25)
+}
+
+const fn default_retry_max_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+const fn default_retry_budget_percent() -> u32 {
+    20
+}
+
+const fn default_retry_min_per_second() -> u32 {
+    10
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: default_retry_attempts(),
+            on: default_retry_on(),
+            non_idempotent: false,
+            backoff: default_retry_backoff(),
+            max_backoff: default_retry_max_backoff(),
+            budget_percent: default_retry_budget_percent(),
+            min_retries_per_second: default_retry_min_per_second(),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+
+/// Maps urls of the upstream's server in responses back to the url the client used, undoing `remove_match`
+/// and the path of the server's address (ex. `Location: http://10.0.0.2:3000/login` becomes `Location: https://example.com/app/login`).
+/// Nothing is rewritten by default
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    /// Rewrite `Location` and `Content-Location` headers pointing at the server
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub location: bool,
+    /// Rewrite the `Path` of cookies set by the server
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub cookie_path: bool,
+    /// Replacements for the `Domain` of cookies set by the server, ex. `{ "app.internal": "example.com" }`
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub cookie_domains: HashMap<String, String>,
+    /// Remove the `Domain` of cookies for the domain of the server's `Host` which aren't replaced by `cookie_domains`,
+    /// so they are kept by the client for the domain it used
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub remove_cookie_domain: bool,
+}
+
+impl Rewrite {
+    /// Whether any `Set-Cookie` is changed
+    pub(crate) fn rewrites_cookies(&self) -> bool {
+        self.cookie_path || self.remove_cookie_domain || !self.cookie_domains.is_empty()
+    }
+}
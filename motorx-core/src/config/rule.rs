@@ -1,9 +1,13 @@
 use std::{borrow::Cow, collections::HashMap, hash::Hash, time::Duration};
 
-use http::Method;
-use hyper::{body::Incoming, Request};
+use http::{header::HOST, HeaderValue, Method};
+use hyper::Request;
 
-use super::match_type::MatchType;
+use super::{
+    authentication::Authentication, match_type::MatchType, HeaderOps, Retry, Rewrite, Timeouts,
+    Upstream,
+};
+use crate::handler::Handler;
 
 #[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
 #[derive(Debug, PartialEq, Clone)]
@@ -13,22 +17,59 @@ pub struct Rule {
     /// Removes matched section from the path. Only works for start
     #[cfg_attr(feature = "serde-config", serde(default))]
     pub remove_match: bool,
-    /// Rule that headers must match
+    /// Rule that headers must match, `host` is matched against the uri's authority if there is one,
+    /// since http2 requests don't have a `Host` header
     pub match_headers: Option<HashMap<String, MatchType>>,
-    /// Where the request, should match a key in the `upstreams` object
+    /// Where the request, should match a key in the `upstreams` object, or the name of a handler in `handlers`
     pub upstream: String,
     /// Settings for caching, by providing this you opt into caching for this rule based on the methods provided in `cache_methods` (defaults to ['GET'])
     pub cache: Option<CacheSettings>,
+    /// Retry requests which fail, failing to connect is retried once by default
+    pub retry: Option<Retry>,
+    /// Overrides the upstream's timeouts for requests matching this rule
+    pub timeouts: Option<Timeouts>,
+    /// Overrides the upstream's `authentication` for requests matching this rule.
+    /// Rules served by a handler can't have it
+    pub authentication: Option<Authentication>,
+    /// Changes made to requests matching this rule, they can't change `Host` (see `host`)
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub request_headers: HeaderOps,
+    /// Changes made to responses to requests matching this rule
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub response_headers: HeaderOps,
+    /// Overrides the upstream's `preserve_host`, `true` also takes priority over the upstream's `host`
+    pub preserve_host: Option<bool>,
+    /// `Host` sent with requests matching this rule, takes priority over every other host setting
+    #[cfg_attr(
+        feature = "serde-config",
+        serde(default, with = "super::headers::de_opt_header_value")
+    )]
+    pub host: Option<HeaderValue>,
+    /// Mapping of the server's urls in `Location`, `Content-Location` and `Set-Cookie` back to the client's url
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub rewrite: Rewrite,
     /// Key into Slab containing cache for this rule, it is overridden on startup
     #[cfg_attr(feature = "serde-config", serde(default))]
     pub cache_key: usize,
     /// Key into Slab containing upstreams, it is overridden on startup
     #[cfg_attr(feature = "serde-config", serde(default))]
     pub upstream_key: usize,
+    /// Handler serving requests in place of an upstream, it is set on startup if `upstream` names one
+    #[cfg_attr(feature = "serde-config", serde(skip))]
+    pub handler: Option<Handler>,
+}
+
+/// Host the client sent `req` to, from the uri's authority if there is one,
+/// since http2 requests carry it in `:authority` instead of `Host`
+pub(crate) fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    req.uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| req.headers().get(HOST)?.to_str().ok())
 }
 
 impl Rule {
-    pub fn matches(&self, req: &Request<Incoming>) -> bool {
+    pub fn matches<B>(&self, req: &Request<B>) -> bool {
         let path_result = self.path.matches(req.uri().path());
 
         if !path_result.is_match() {
@@ -37,12 +78,20 @@ impl Rule {
 
         if let Some(headers) = self.match_headers.as_ref() {
             for (header, pattern) in headers {
-                if let Some(header_in_req) = req.headers().get(header) {
-                    // TODO: handle non-utf8 header values
-                    if !pattern
-                        .matches(header_in_req.to_str().unwrap_or_default())
-                        .is_match()
-                    {
+                // TODO: handle non-utf8 header values
+                let header_value = |name: &str| {
+                    req.headers()
+                        .get(name)
+                        .map(|value| value.to_str().unwrap_or_default())
+                };
+                let value_in_req = if header.eq_ignore_ascii_case(HOST.as_str()) {
+                    request_host(req)
+                } else {
+                    header_value(header)
+                };
+
+                if let Some(value_in_req) = value_in_req {
+                    if !pattern.matches(value_in_req).is_match() {
                         return false;
                     }
                 } else {
@@ -55,6 +104,36 @@ impl Rule {
         true
     }
 
+    /// `Host` to send upstream for `req`, `None` means the authority of the server it is sent to
+    pub(crate) fn upstream_host<B>(
+        &self,
+        upstream: &Upstream,
+        req: &Request<B>,
+    ) -> Option<HeaderValue> {
+        let client_host = || {
+            req.headers().get(HOST).cloned().or_else(|| {
+                req.uri()
+                    .authority()
+                    .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+            })
+        };
+
+        if let Some(host) = &self.host {
+            return Some(host.clone());
+        }
+        if self.preserve_host == Some(true) {
+            return client_host();
+        }
+        if let Some(host) = &upstream.host {
+            return Some(host.clone());
+        }
+        if self.preserve_host.is_none() && upstream.preserve_host {
+            return client_host();
+        }
+
+        None
+    }
+
     pub fn remove_match<'a>(&self, path: &'a str) -> Cow<'a, str> {
         if self.remove_match {
             match &self.path {
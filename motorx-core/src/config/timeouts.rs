@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+/// Limits on how long upstream traffic may take, requests which hit one are answered with 504.
+/// `connect` and `response` also limit authentication requests.
+/// Timeouts which aren't set don't limit anything
+#[cfg_attr(feature = "serde-config", derive(serde::Deserialize))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time to open a connection to a server. Waiting for a free connection when `max_connections`
+    /// are in use is limited by the pool's `max_queue_wait` instead
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub connect: Option<Duration>,
+    /// Time for a server to send the response's headers once the request was sent
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub response: Option<Duration>,
+    /// Time for the whole exchange including every retry, until the response's body is finished.
+    /// Once the response's headers were sent to the client, hitting it cuts off the body instead
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub request: Option<Duration>,
+    /// Longest a server may go without sending more of the response's body
+    #[cfg_attr(feature = "serde-config", serde(default))]
+    pub idle: Option<Duration>,
+}
+
+impl Timeouts {
+    /// These timeouts, with any set in `overrides` taking their place
+    pub fn overridden_by(&self, overrides: Option<&Timeouts>) -> Timeouts {
+        let Some(overrides) = overrides else {
+            return *self;
+        };
+
+        Timeouts {
+            connect: overrides.connect.or(self.connect),
+            response: overrides.response.or(self.response),
+            request: overrides.request.or(self.request),
+            idle: overrides.idle.or(self.idle),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use http::{uri::InvalidUri, HeaderValue, Uri};
+
+/// Address of an upstream server, a uri (ex. `http://localhost:3000`)
+/// or the path of a unix socket (ex. `unix:/run/app.sock`).
+/// A path in the uri (ex. `http://localhost:3000/service-a`) is prefixed to request paths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamAddr {
+    Uri(Uri),
+    Unix(PathBuf),
+}
+
+impl UpstreamAddr {
+    /// Uri requests to this server are relative to, unix sockets are reached as `http://localhost`
+    pub fn uri(&self) -> Uri {
+        match self {
+            UpstreamAddr::Uri(uri) => uri.clone(),
+            UpstreamAddr::Unix(_) => Uri::from_static("http://localhost"),
+        }
+    }
+
+    /// Path requests to this server are prefixed with, without a trailing slash (ex. `/service-a`).
+    /// Empty if the uri has no path
+    pub fn base_path(&self) -> &str {
+        match self {
+            UpstreamAddr::Uri(uri) => uri.path().trim_end_matches('/'),
+            UpstreamAddr::Unix(_) => "",
+        }
+    }
+
+    /// `Host` header sent to this server
+    pub fn host(&self) -> HeaderValue {
+        match self {
+            UpstreamAddr::Uri(uri) => uri
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+                .unwrap_or_else(|| HeaderValue::from_static("localhost")),
+            UpstreamAddr::Unix(_) => HeaderValue::from_static("localhost"),
+        }
+    }
+}
+
+impl From<Uri> for UpstreamAddr {
+    fn from(uri: Uri) -> Self {
+        UpstreamAddr::Uri(uri)
+    }
+}
+
+impl FromStr for UpstreamAddr {
+    type Err = InvalidUri;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(UpstreamAddr::Unix(path.into())),
+            None => Ok(UpstreamAddr::Uri(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamAddr::Uri(uri) => write!(f, "{uri}"),
+            UpstreamAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(feature = "serde-config")]
+mod de_upstream_addr {
+    use serde::de::{Deserialize, Visitor};
+
+    use super::UpstreamAddr;
+
+    struct UpstreamAddrVisitor;
+
+    impl Visitor<'_> for UpstreamAddrVisitor {
+        type Value = UpstreamAddr;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                formatter,
+                "A uri or unix socket path (ex. http://localhost:3000 or unix:/run/app.sock)"
+            )
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            v.parse()
+                .map_err(|e: http::uri::InvalidUri| E::custom(e.to_string()))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for UpstreamAddr {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_str(UpstreamAddrVisitor)
+        }
+    }
+}
@@ -1,105 +1,701 @@
 use std::{
+    collections::VecDeque,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-use http::Uri;
+use bytes::Bytes;
+use http::{uri::PathAndQuery, Request, Response, Uri, Version};
+use http_body_util::combinators::BoxBody;
 use hyper::{
     body::Incoming,
-    client::{self, conn::http1::SendRequest},
+    client::conn::{http1, http2},
 };
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use tokio::{
     select,
-    sync::{
-        mpsc::{self, Receiver, Sender},
-        Mutex, Semaphore,
-    },
+    sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{
+    cfg_logging,
+    config::{Pool, UpstreamAddr},
+    connector::{Connector, Endpoint, UpstreamStream},
+    proxy_protocol::ClientAddrs,
+    timeout::within,
+    Upstreams,
 };
 
-use crate::{cfg_logging, tcp_connect};
+/// Body of requests sent to upstreams
+pub(crate) type ProxyBody = BoxBody<Bytes, crate::Error>;
+
+/// Requests an http2 connection should carry before another is opened, if `max_connections` allows
+const H2_STREAMS_PER_CONNECTION: usize = 100;
+
+/// Time between evicting expired connections and opening connections up to `min_idle`
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Handler asks for sender (ConnPool::get_sender)
-///     - if mpsc::recv is first -> use existing connection
-///     - else (whichever is first):
-///         - mpsc::recv -> use connection that was added back to the pool
-///         - semaphore::acquire_owned -> open new connection, and pass semaphore to connection polling task
+///     - if an http2 connection is open with room for more streams -> share it
+///     - if an idle http1 connection hasn't expired -> use it
+///     - if a semaphore permit is free -> open new connection, and pass semaphore to connection polling task
+///     - else close an idle connection opened for another client, if there is one, join the upstream's queue,
+///       erroring if it is full, and (whichever is first):
+///         - Notify::notified -> a connection was added back to the pool, check again
+///         - semaphore::acquire_owned -> open new connection
+///         - `max_queue_wait` passes -> error
+///
+/// Connections starting with a PROXY protocol header are only reused for the client the header names
 #[derive(Debug)]
 pub(crate) struct ConnPool {
     /// Limit number of connections allowed to be opened at once
     semaphore: Arc<Semaphore>,
-    receiver: Mutex<Receiver<SendRequest<Incoming>>>,
-    /// Keep channel alive forever, send clones to handler so they can add sender back into queue
-    sender: Sender<SendRequest<Incoming>>,
-    uri: Uri,
+    /// Held while checking out an http1 connection or opening a new one, so requests are served in order
+    checkout: Mutex<()>,
+    /// Shared with handlers so they can add connections back into the queue
+    idle: Arc<IdleConns>,
+    /// Open http2 connections, each shared by many requests at once
+    h2_conns: std::sync::Mutex<Vec<H2Conn>>,
+    min_idle: usize,
+    /// Requests waiting for a connection to any of the upstream's servers, shared by their pools
+    queue: Arc<RequestQueue>,
+    addr: UpstreamAddr,
+    /// Scheme and authority of the server, http2 requests need an absolute uri
+    origin: Uri,
+    connector: Arc<Connector>,
+    endpoint: Endpoint,
+    /// Number of requests waiting for or holding a connection from this pool
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Requests waiting for a connection across an upstream's pools, rejecting requests past its limits
+#[derive(Debug)]
+pub(crate) struct RequestQueue {
+    waiting: AtomicUsize,
+    /// Requests turned away since startup
+    rejected: AtomicU64,
+    max_queued: Option<usize>,
+    max_wait: Option<Duration>,
+}
+
+/// Counts a request as waiting in a [`RequestQueue`] until dropped
+#[derive(Debug)]
+struct Queued<'a>(&'a RequestQueue);
+
+/// Http1 connections not in use, the most recently used at the back
+#[derive(Debug)]
+struct IdleConns {
+    conns: std::sync::Mutex<VecDeque<IdleConn>>,
+    /// Notified when a connection is added back
+    returned: Notify,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct IdleConn {
+    sender: http1::SendRequest<ProxyBody>,
+    /// Client named by the connection's PROXY protocol header
+    client: Option<ClientAddrs>,
+    opened: Instant,
+    idle_since: Instant,
+}
+
+#[derive(Debug)]
+struct H2Conn {
+    sender: http2::SendRequest<ProxyBody>,
+    /// Client named by the connection's PROXY protocol header
+    client: Option<ClientAddrs>,
+    /// Number of requests using this connection
+    streams: Arc<AtomicUsize>,
+    opened: Instant,
+    /// Last time a request was given this connection
+    last_used: Instant,
+}
+
+/// Sends requests over a connection of either http version
+#[derive(Debug)]
+pub(crate) enum Sender {
+    Http1(http1::SendRequest<ProxyBody>),
+    Http2 {
+        sender: http2::SendRequest<ProxyBody>,
+        origin: Uri,
+    },
 }
 
 #[derive(Debug)]
 pub(crate) struct PooledConn {
-    sender: Sender<SendRequest<Incoming>>,
-    conn: Option<SendRequest<Incoming>>,
+    pool: Arc<IdleConns>,
+    conn: Option<Sender>,
+    /// Client named by the connection's PROXY protocol header
+    client: Option<ClientAddrs>,
+    /// When the connection was opened, it is closed instead of returned once past `max_lifetime`
+    opened: Instant,
+    /// Counts this request against its http2 connection's streams
+    _stream: Option<InFlight>,
+    _in_flight: InFlight,
+}
+
+/// Counts a request as in flight until dropped
+#[derive(Debug)]
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(counter))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl ConnPool {
-    pub(crate) fn new(uri: Uri, max_connections: usize) -> Self {
-        let (sender, receiver) = mpsc::channel::<SendRequest<Incoming>>(max_connections);
+    pub(crate) fn new(
+        addr: UpstreamAddr,
+        connector: Arc<Connector>,
+        endpoint: Endpoint,
+        max_connections: usize,
+        pool: &Pool,
+        queue: Arc<RequestQueue>,
+    ) -> Self {
+        let origin = Uri::builder()
+            .scheme(if endpoint.uses_tls() { "https" } else { "http" })
+            .authority(addr.uri().authority().unwrap().clone())
+            .path_and_query("/")
+            .build()
+            .unwrap();
+
         ConnPool {
             semaphore: Arc::new(Semaphore::new(max_connections)),
-            sender,
-            receiver: Mutex::new(receiver),
-            uri,
+            checkout: Mutex::new(()),
+            idle: Arc::new(IdleConns {
+                conns: Default::default(),
+                returned: Notify::new(),
+                idle_timeout: pool.idle_timeout,
+                max_lifetime: pool.max_lifetime,
+            }),
+            h2_conns: Default::default(),
+            min_idle: pool.min_idle,
+            queue,
+            addr,
+            origin,
+            connector,
+            endpoint,
+            in_flight: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    pub(crate) async fn get_sender(&self) -> Result<PooledConn, crate::Error> {
+    /// Number of requests currently waiting for or using a connection from this pool
+    #[inline]
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Opens a new connection to this pool's server for `client`, outside of the pool
+    pub(crate) async fn connect(
+        &self,
+        client: Option<&ClientAddrs>,
+    ) -> std::io::Result<UpstreamStream> {
+        self.connector.connect(&self.endpoint, client).await
+    }
+
+    /// Opens a new connection and performs the http handshake, `permit` is held until the connection closes.
+    /// Connecting fails with [`crate::Error::Timeout`] if it takes longer than `connect_timeout`
+    pub(crate) async fn open(
+        &self,
+        permit: Option<OwnedSemaphorePermit>,
+        client: Option<&ClientAddrs>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Sender, crate::Error> {
+        let stream = within(connect_timeout, self.connect(client))
+            .await
+            .map_err(|_| crate::Error::Timeout("connecting to the upstream"))??;
+
+        if self.connector.is_http2(&stream) {
+            let (sender, conn) = http2::Builder::new(TokioExecutor::new())
+                .handshake(TokioIo::new(stream))
+                .await?;
+
+            tokio::task::spawn(async move {
+                if let Err(err) = conn.await {
+                    cfg_logging! {error!("Connection failed: {:?}", err);}
+                }
+
+                drop(permit);
+            });
+
+            Ok(Sender::Http2 {
+                sender,
+                origin: self.origin.clone(),
+            })
+        } else {
+            let (sender, conn) = http1::Builder::new()
+                .preserve_header_case(true)
+                .title_case_headers(true)
+                .handshake(TokioIo::new(stream))
+                .await?;
+
+            tokio::task::spawn(async move {
+                if let Err(err) = conn.with_upgrades().await {
+                    cfg_logging! {error!("Connection failed: {:?}", err);}
+                }
+
+                // move semaphore into this task so it is returned when connection is closed
+                drop(permit);
+            });
+
+            Ok(Sender::Http1(sender))
+        }
+    }
+
+    /// Connection to send a request from `client` over, `connect_timeout` limits opening a new one
+    /// but not waiting in the queue
+    pub(crate) async fn get_sender(
+        &self,
+        client: Option<&ClientAddrs>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<PooledConn, crate::Error> {
+        let in_flight = InFlight::new(&self.in_flight);
+        // Only connections starting with a PROXY protocol header belong to a client
+        let client = client
+            .copied()
+            .filter(|_| self.connector.sends_proxy_header());
+
+        if let Some((conn, stream)) = self.shared_h2_conn(client) {
+            return Ok(self.pooled(conn, client, Instant::now(), Some(stream), in_flight));
+        }
+
+        // Requests only count against the queue's limits once they have to wait for a connection
+        let deadline = self.queue.max_wait.map(|wait| Instant::now() + wait);
+        let mut queued = None;
+
         // only return if the SendRequest's underlying connection exists still
         // loop until we get a sender that meets this criteria
-        let mut receiver = self.receiver.lock().await;
+        let _checkout = match self.checkout.try_lock() {
+            Ok(checkout) => checkout,
+            Err(_) => {
+                queued = Some(self.queue.enter()?);
+                self.queue.wait(deadline, self.checkout.lock()).await?
+            }
+        };
+
+        // Another request may have opened an http2 connection while we waited for the lock
+        if let Some((conn, stream)) = self.shared_h2_conn(client) {
+            return Ok(self.pooled(conn, client, Instant::now(), Some(stream), in_flight));
+        }
+
         loop {
-            let mut conn = select! {
-                biased;
-                // If there is a conn in the queue already, use that first
-                sender = receiver.recv() => {
-                    cfg_logging! {trace!("Reusing connection to: {}", self.uri);}
-                    Ok::<_, crate::Error>(sender.unwrap())
-                },
-                // Otherwise, check if new connections are allowed to be opened
-                permit = Arc::clone(&self.semaphore).acquire_owned() => {
-                    let permit = permit.unwrap();
-                    cfg_logging! {info!("Opened new connection to: {}", self.uri);}
-                    let stream = tcp_connect(self.uri.authority().unwrap().as_str()).await?;
-                    let (sender, conn) = client::conn::http1::Builder::new()
-                        .preserve_header_case(true)
-                        .title_case_headers(true)
-                        .handshake(TokioIo::new(stream))
-                        .await?;
-
-                    tokio::task::spawn(async move {
-                        if let Err(err) = conn.with_upgrades().await {
-                            cfg_logging! {error!("Connection failed: {:?}", err);}
+            // Listen before checking the queue, so a connection added back in between isn't missed
+            let returned = self.idle.returned.notified();
+            tokio::pin!(returned);
+            returned.as_mut().enable();
+
+            // If there is a conn in the queue already, use that first
+            let (conn, opened) = if let Some(idle) = self.idle.take(client.as_ref()) {
+                cfg_logging! {trace!("Reusing connection to: {}", self.addr);}
+                (Sender::Http1(idle.sender), idle.opened)
+            } else {
+                let permit = match Arc::clone(&self.semaphore).try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        // Its permit is freed once the connection closes
+                        if self.close_idle_for_other_client(client.as_ref()) {
+                            cfg_logging! {debug!("Closed a connection to {} opened for another client", self.addr);}
+                        }
+                        if queued.is_none() {
+                            queued = Some(self.queue.enter()?);
                         }
 
-                        // move semaphore into this task so it is returned when connection is closed
-                        drop(permit);
+                        let wait = async {
+                            select! {
+                                biased;
+                                // A connection was added back to the queue
+                                _ = &mut returned => None,
+                                // Otherwise, wait until new connections are allowed to be opened
+                                permit = Arc::clone(&self.semaphore).acquire_owned() => Some(permit.unwrap()),
+                            }
+                        };
+                        match self.queue.wait(deadline, wait).await? {
+                            Some(permit) => permit,
+                            None => continue,
+                        }
+                    }
+                };
+
+                cfg_logging! {info!("Opened new connection to: {}", self.addr);}
+                (
+                    self.open(Some(permit), client.as_ref(), connect_timeout)
+                        .await?,
+                    Instant::now(),
+                )
+            };
+
+            match conn {
+                Sender::Http1(mut sender) => {
+                    // check that underlying conn exists
+                    if sender.ready().await.is_ok() {
+                        return Ok(self.pooled(
+                            Sender::Http1(sender),
+                            client,
+                            opened,
+                            None,
+                            in_flight,
+                        ));
+                    }
+                }
+                Sender::Http2 { sender, origin } => {
+                    let streams = Arc::new(AtomicUsize::new(0));
+                    let stream = InFlight::new(&streams);
+                    self.h2_conns.lock().unwrap().push(H2Conn {
+                        sender: sender.clone(),
+                        client,
+                        streams,
+                        opened,
+                        last_used: opened,
                     });
 
-                    Ok(sender)
+                    return Ok(self.pooled(
+                        Sender::Http2 { sender, origin },
+                        client,
+                        opened,
+                        Some(stream),
+                        in_flight,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Least busy open http2 connection for `client`, if it has room for another stream
+    /// or no more connections can be opened
+    fn shared_h2_conn(&self, client: Option<ClientAddrs>) -> Option<(Sender, InFlight)> {
+        let mut h2_conns = self.h2_conns.lock().unwrap();
+        h2_conns.retain(|conn| !conn.sender.is_closed() && !self.h2_expired(conn));
+
+        let conn = h2_conns
+            .iter_mut()
+            .filter(|conn| conn.client == client)
+            .min_by_key(|conn| conn.streams.load(Ordering::Relaxed))?;
+
+        if conn.streams.load(Ordering::Relaxed) >= H2_STREAMS_PER_CONNECTION
+            && self.semaphore.available_permits() > 0
+        {
+            return None;
+        }
+
+        cfg_logging! {trace!("Sharing http2 connection to: {}", self.addr);}
+        conn.last_used = Instant::now();
+        Some((
+            Sender::Http2 {
+                sender: conn.sender.clone(),
+                origin: self.origin.clone(),
+            },
+            InFlight::new(&conn.streams),
+        ))
+    }
+
+    /// Closes the least recently used idle connection opened for a client other than `client`,
+    /// returns whether there was one
+    fn close_idle_for_other_client(&self, client: Option<&ClientAddrs>) -> bool {
+        if self.idle.close_oldest_not_for(client) {
+            return true;
+        }
+
+        let mut h2_conns = self.h2_conns.lock().unwrap();
+        let oldest = h2_conns
+            .iter()
+            .enumerate()
+            .filter(|(_, conn)| {
+                conn.client.as_ref() != client && conn.streams.load(Ordering::Relaxed) == 0
+            })
+            .min_by_key(|(_, conn)| conn.last_used)
+            .map(|(index, _)| index);
+        oldest.map(|index| h2_conns.remove(index)).is_some()
+    }
+
+    /// Whether an http2 connection should stop being shared, requests already using it are finished
+    fn h2_expired(&self, conn: &H2Conn) -> bool {
+        let idle = conn.streams.load(Ordering::Relaxed) == 0;
+
+        self.idle
+            .max_lifetime
+            .is_some_and(|max| conn.opened.elapsed() >= max)
+            || (idle
+                && self
+                    .idle
+                    .idle_timeout
+                    .is_some_and(|timeout| conn.last_used.elapsed() >= timeout))
+    }
+
+    /// Closes expired connections, then opens connections until `min_idle` are ready
+    pub(crate) async fn maintain(&self) {
+        self.idle.evict_expired();
+        self.h2_conns
+            .lock()
+            .unwrap()
+            .retain(|conn| !conn.sender.is_closed() && !self.h2_expired(conn));
+
+        // Connections opened ahead of time can't say which client they are for
+        if self.connector.sends_proxy_header() {
+            return;
+        }
+
+        while self.idle_count() < self.min_idle {
+            // Only use permits which are free, requests come first
+            let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_owned() else {
+                return;
+            };
+
+            match self.open(Some(permit), None, None).await {
+                Ok(Sender::Http1(sender)) => {
+                    cfg_logging! {debug!("Opened idle connection to: {}", self.addr);}
+                    let now = Instant::now();
+                    self.idle.put(IdleConn {
+                        sender,
+                        client: None,
+                        opened: now,
+                        idle_since: now,
+                    });
+                }
+                Ok(Sender::Http2 { sender, .. }) => {
+                    cfg_logging! {debug!("Opened idle http2 connection to: {}", self.addr);}
+                    let now = Instant::now();
+                    self.h2_conns.lock().unwrap().push(H2Conn {
+                        sender,
+                        client: None,
+                        streams: Arc::new(AtomicUsize::new(0)),
+                        opened: now,
+                        last_used: now,
+                    });
+                }
+                Err(_err) => {
+                    cfg_logging! {warn!("Failed to open idle connection to {}: {_err}", self.addr);}
+                    return;
                 }
-            }?;
-
-            // check that underlying conn exists
-            if let Ok(_) = conn.ready().await {
-                return Ok(PooledConn {
-                    sender: self.sender.clone(),
-                    conn: Some(conn),
-                });
             }
         }
     }
+
+    /// Number of open connections which aren't being used
+    fn idle_count(&self) -> usize {
+        let h2_idle = self
+            .h2_conns
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|conn| conn.streams.load(Ordering::Relaxed) == 0)
+            .count();
+
+        self.idle.conns.lock().unwrap().len() + h2_idle
+    }
+
+    #[inline]
+    fn pooled(
+        &self,
+        conn: Sender,
+        client: Option<ClientAddrs>,
+        opened: Instant,
+        stream: Option<InFlight>,
+        in_flight: InFlight,
+    ) -> PooledConn {
+        PooledConn {
+            pool: Arc::clone(&self.idle),
+            conn: Some(conn),
+            client,
+            opened,
+            _stream: stream,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl RequestQueue {
+    pub(crate) fn new(pool: &Pool) -> Self {
+        Self {
+            waiting: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            max_queued: pool.max_queued,
+            max_wait: pool.max_queue_wait,
+        }
+    }
+
+    /// Number of requests currently waiting for a connection
+    #[inline]
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests rejected for waiting too long or finding the queue full
+    #[inline]
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Joins the queue, errors if it is full
+    fn enter(&self) -> Result<Queued<'_>, crate::Error> {
+        let waiting = self.waiting.fetch_add(1, Ordering::Relaxed);
+        let queued = Queued(self);
+
+        if self.max_queued.is_some_and(|max| waiting >= max) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(crate::Error::QueueFull);
+        }
+
+        Ok(queued)
+    }
+
+    /// Runs `fut` until `deadline`, errors if it passes first
+    async fn wait<F: std::future::Future>(
+        &self,
+        deadline: Option<Instant>,
+        fut: F,
+    ) -> Result<F::Output, crate::Error> {
+        let Some(deadline) = deadline else {
+            return Ok(fut.await);
+        };
+
+        tokio::time::timeout_at(deadline.into(), fut)
+            .await
+            .map_err(|_| {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                crate::Error::QueueTimeout
+            })
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl IdleConns {
+    /// Most recently used connection for `client` which hasn't expired, expired connections are closed
+    fn take(&self, client: Option<&ClientAddrs>) -> Option<IdleConn> {
+        let mut conns = self.conns.lock().unwrap();
+        conns.retain(|conn| !self.expired(conn));
+
+        let index = conns
+            .iter()
+            .rposition(|conn| conn.client.as_ref() == client)?;
+        conns.remove(index)
+    }
+
+    /// Closes the least recently used connection which isn't for `client`, returns whether there was one
+    fn close_oldest_not_for(&self, client: Option<&ClientAddrs>) -> bool {
+        let mut conns = self.conns.lock().unwrap();
+
+        let index = conns.iter().position(|conn| conn.client.as_ref() != client);
+        index.and_then(|index| conns.remove(index)).is_some()
+    }
+
+    /// Adds a connection back to the queue, unless it can't be used anymore
+    fn put(&self, conn: IdleConn) {
+        if conn.sender.is_closed() || self.expired(&conn) {
+            return;
+        }
+
+        self.conns.lock().unwrap().push_back(conn);
+        self.returned.notify_one();
+    }
+
+    fn evict_expired(&self) {
+        self.conns
+            .lock()
+            .unwrap()
+            .retain(|conn| !conn.sender.is_closed() && !self.expired(conn));
+    }
+
+    fn expired(&self, conn: &IdleConn) -> bool {
+        self.idle_timeout
+            .is_some_and(|timeout| conn.idle_since.elapsed() >= timeout)
+            || self
+                .max_lifetime
+                .is_some_and(|max| conn.opened.elapsed() >= max)
+    }
+}
+
+/// Spawns a task for each upstream which keeps its pools' connections fresh,
+/// it stops once `upstreams` is dropped
+pub(crate) fn spawn_pool_maintenance(upstreams: &Arc<Upstreams>) {
+    for (key, (upstream, _)) in upstreams.iter().enumerate() {
+        let pool = &upstream.pool;
+        if pool.idle_timeout.is_none() && pool.max_lifetime.is_none() && pool.min_idle == 0 {
+            continue;
+        }
+        let upstreams = Arc::downgrade(upstreams);
+
+        tokio::spawn(run_pool_maintenance(upstreams, key));
+    }
+}
+
+async fn run_pool_maintenance(upstreams: Weak<Upstreams>, key: usize) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let Some(backends) = upstreams
+            .upgrade()
+            .map(|upstreams| upstreams[key].1.backends())
+        else {
+            return;
+        };
+
+        for backend in backends {
+            backend.pool.maintain().await;
+        }
+    }
+}
+
+impl Sender {
+    /// Waits until the connection can send a request, errors if it closed
+    pub(crate) async fn ready(&mut self) -> Result<(), hyper::Error> {
+        match self {
+            Sender::Http1(sender) => sender.ready().await,
+            Sender::Http2 { sender, .. } => sender.ready().await,
+        }
+    }
+
+    pub(crate) async fn send_request(
+        &mut self,
+        mut req: Request<ProxyBody>,
+    ) -> Result<Response<Incoming>, hyper::Error> {
+        match self {
+            Sender::Http1(sender) => sender.send_request(req).await,
+            Sender::Http2 { sender, origin } => {
+                let mut parts = origin.clone().into_parts();
+                parts.path_and_query = req
+                    .uri()
+                    .path_and_query()
+                    .cloned()
+                    .or_else(|| Some(PathAndQuery::from_static("/")));
+                *req.uri_mut() = Uri::from_parts(parts).unwrap();
+                *req.version_mut() = Version::HTTP_2;
+
+                sender.send_request(req).await
+            }
+        }
+    }
+}
+
+impl PooledConn {
+    /// Closes the connection instead of handing it back to the pool, for connections left mid request
+    pub(crate) fn discard(mut self) {
+        self.conn.take();
+    }
 }
 
 impl Deref for PooledConn {
-    type Target = SendRequest<Incoming>;
+    type Target = Sender;
 
     fn deref(&self) -> &Self::Target {
         self.conn.as_ref().unwrap()
@@ -114,8 +710,14 @@ impl DerefMut for PooledConn {
 
 impl Drop for PooledConn {
     fn drop(&mut self) {
-        if let Err(err) = self.sender.try_send(self.conn.take().unwrap()) {
-            cfg_logging! {tracing::error!("Failed to send conn back to pool! {err:?}");}
-        };
+        // http2 connections stay shared in the pool, only http1 connections are handed back
+        if let Some(Sender::Http1(sender)) = self.conn.take() {
+            self.pool.put(IdleConn {
+                sender,
+                client: self.client,
+                opened: self.opened,
+                idle_since: Instant::now(),
+            });
+        }
     }
 }
@@ -0,0 +1,344 @@
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{ProxyProtocol, Upstream, UpstreamAddr, UpstreamProtocol},
+    dns::{self, Resolver},
+    proxy_protocol::{self, ClientAddrs},
+    tcp_connect,
+};
+
+/// Opens transport connections to an upstream's servers, holding the settings they share
+#[derive(Debug)]
+pub(crate) struct Connector {
+    protocol: UpstreamProtocol,
+    resolver: Resolver,
+    /// Idle time before TCP keepalive probes are sent
+    keepalive: Option<Duration>,
+    proxy_protocol: Option<ProxyProtocol>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsSettings>,
+}
+
+#[cfg(feature = "tls")]
+#[derive(Debug)]
+struct TlsSettings {
+    config: Arc<rustls::ClientConfig>,
+    sni: Option<rustls::pki_types::ServerName<'static>>,
+}
+
+/// Where a single upstream server is reached
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    addr: EndpointAddr,
+    tls: bool,
+    #[cfg(feature = "tls")]
+    server_name: Option<rustls::pki_types::ServerName<'static>>,
+}
+
+#[derive(Debug, Clone)]
+enum EndpointAddr {
+    /// Host resolved when connecting
+    Tcp { host: String, port: u16 },
+    /// Address a hostname was resolved to ahead of time
+    Socket(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Connection to an upstream server
+pub(crate) enum UpstreamStream {
+    Plain(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+#[inline]
+fn uses_tls(addr: &UpstreamAddr, upstream: &Upstream) -> bool {
+    match addr {
+        UpstreamAddr::Uri(uri) => {
+            uri.scheme() == Some(&http::uri::Scheme::HTTPS) || upstream.tls.is_some()
+        }
+        UpstreamAddr::Unix(_) => upstream.tls.is_some(),
+    }
+}
+
+impl Connector {
+    pub(crate) fn new(upstream: &Upstream, resolver: &Resolver) -> Result<Self, crate::Error> {
+        // Dynamic upstreams have no servers until requests come in, so their template's scheme is checked
+        let use_tls = upstream.tls.is_some()
+            || upstream
+                .all_servers()
+                .any(|server| uses_tls(&server.addr, upstream))
+            || upstream
+                .dynamic
+                .as_ref()
+                .is_some_and(|dynamic| dynamic.addr.starts_with("https://"));
+
+        #[cfg(feature = "tls")]
+        let tls = if use_tls {
+            let tls = upstream.tls.clone().unwrap_or_default();
+            let mut config = crate::tls::client::client_config(&tls)?;
+            config.alpn_protocols = match upstream.protocol {
+                UpstreamProtocol::Http1 => vec![b"http/1.1".to_vec()],
+                UpstreamProtocol::Http2 => vec![b"h2".to_vec()],
+                UpstreamProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            };
+
+            Some(TlsSettings {
+                config: Arc::new(config),
+                sni: tls
+                    .sni
+                    .as_deref()
+                    .map(crate::tls::client::server_name)
+                    .transpose()?,
+            })
+        } else {
+            None
+        };
+
+        #[cfg(not(feature = "tls"))]
+        if use_tls {
+            return Err(crate::Error::Config(
+                "Connecting to upstreams over tls requires the `tls` feature".into(),
+            ));
+        }
+
+        Ok(Self {
+            protocol: upstream.protocol,
+            resolver: resolver.clone(),
+            keepalive: upstream.pool.keepalive,
+            proxy_protocol: upstream.proxy_protocol,
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    /// Whether requests over `stream` should use http2
+    pub(crate) fn is_http2(&self, stream: &UpstreamStream) -> bool {
+        match self.protocol {
+            UpstreamProtocol::Http1 => false,
+            UpstreamProtocol::Http2 => true,
+            UpstreamProtocol::Auto => stream.alpn_h2(),
+        }
+    }
+
+    /// Resolves how to reach the server at `addr`
+    pub(crate) fn endpoint(
+        &self,
+        addr: &UpstreamAddr,
+        upstream: &Upstream,
+    ) -> Result<Endpoint, crate::Error> {
+        let use_tls = uses_tls(addr, upstream);
+        let uri = match addr {
+            UpstreamAddr::Uri(uri) => uri,
+            UpstreamAddr::Unix(_path) if use_tls => {
+                return Err(crate::Error::Config(format!(
+                    "Tls isn't supported for unix socket upstream {addr}"
+                )))
+            }
+            #[cfg(unix)]
+            UpstreamAddr::Unix(path) => {
+                return Ok(Endpoint {
+                    addr: EndpointAddr::Unix(path.clone()),
+                    tls: false,
+                    #[cfg(feature = "tls")]
+                    server_name: None,
+                })
+            }
+            #[cfg(not(unix))]
+            UpstreamAddr::Unix(_) => {
+                return Err(crate::Error::Config(format!(
+                    "Unix socket upstream {addr} is only supported on unix platforms"
+                )))
+            }
+        };
+        let host = uri
+            .host()
+            .ok_or_else(|| crate::Error::Config(format!("Upstream address {uri} has no host")))?;
+        let port = uri.port_u16().unwrap_or(if use_tls { 443 } else { 80 });
+
+        #[cfg(feature = "tls")]
+        let server_name = match (&self.tls, use_tls) {
+            (Some(tls), true) => Some(match &tls.sni {
+                Some(sni) => sni.clone(),
+                // Ipv6 hosts are in brackets in uris
+                None => crate::tls::client::server_name(host.trim_matches(['[', ']']))?,
+            }),
+            _ => None,
+        };
+
+        Ok(Endpoint {
+            addr: EndpointAddr::Tcp {
+                host: host.to_owned(),
+                port,
+            },
+            tls: use_tls,
+            #[cfg(feature = "tls")]
+            server_name,
+        })
+    }
+
+    /// Whether connections start with a PROXY protocol header, making them specific to one client
+    #[inline]
+    pub(crate) fn sends_proxy_header(&self) -> bool {
+        self.proxy_protocol.is_some()
+    }
+
+    /// Opens a connection to `endpoint`, starting it with a PROXY protocol header for `client` if enabled
+    pub(crate) async fn connect(
+        &self,
+        endpoint: &Endpoint,
+        client: Option<&ClientAddrs>,
+    ) -> io::Result<UpstreamStream> {
+        let proxy_header = self
+            .proxy_protocol
+            .map(|version| proxy_protocol::header(version, client));
+
+        let mut stream = match &endpoint.addr {
+            EndpointAddr::Tcp { host, port } => {
+                let addrs = self
+                    .resolver
+                    .resolve(host)
+                    .await?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect::<Vec<_>>();
+                dns::connect(&addrs).await?
+            }
+            EndpointAddr::Socket(addr) => tcp_connect(addr).await?,
+            #[cfg(unix)]
+            EndpointAddr::Unix(path) => {
+                let mut stream = tokio::net::UnixStream::connect(path).await?;
+                if let Some(header) = proxy_header {
+                    stream.write_all(&header).await?;
+                }
+                return Ok(UpstreamStream::Unix(stream));
+            }
+        };
+
+        // Goes before tls, the server reads it off the raw connection
+        if let Some(header) = proxy_header {
+            stream.write_all(&header).await?;
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            socket2::SockRef::from(&stream)
+                .set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(keepalive))?;
+        }
+
+        #[cfg(feature = "tls")]
+        if let (Some(tls), Some(server_name)) = (&self.tls, &endpoint.server_name) {
+            let stream = tokio_rustls::TlsConnector::from(Arc::clone(&tls.config))
+                .connect(server_name.clone(), stream)
+                .await?;
+            return Ok(UpstreamStream::Tls(Box::new(stream)));
+        }
+
+        // Ex. a dynamic address which only turned out to be https once its variables were filled in
+        if endpoint.tls {
+            return Err(io::Error::other(
+                "The server uses tls, which isn't set up for its upstream",
+            ));
+        }
+
+        Ok(UpstreamStream::Plain(stream))
+    }
+}
+
+impl Endpoint {
+    #[inline]
+    pub(crate) fn uses_tls(&self) -> bool {
+        self.tls
+    }
+
+    /// This endpoint, connecting to `ip` instead of resolving its host
+    pub(crate) fn resolved_to(mut self, ip: IpAddr) -> Self {
+        if let EndpointAddr::Tcp { port, .. } = self.addr {
+            self.addr = EndpointAddr::Socket(SocketAddr::new(ip, port));
+        }
+        self
+    }
+}
+
+impl UpstreamStream {
+    /// Whether http2 was negotiated with ALPN
+    fn alpn_h2(&self) -> bool {
+        match self {
+            UpstreamStream::Plain(_) => false,
+            #[cfg(unix)]
+            UpstreamStream::Unix(_) => false,
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => stream.get_ref().1.alpn_protocol() == Some(b"h2"),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
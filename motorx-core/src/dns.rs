@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{net::TcpStream, select, task::JoinSet};
+
+use crate::{cfg_logging, config::UpstreamAddr, Upstreams};
+
+/// How long to wait on a connection attempt before also trying the next address (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolves upstream hostnames, checking the `hosts` overrides from the config before DNS
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Resolver {
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl Resolver {
+    pub(crate) fn new(hosts: HashMap<String, Vec<IpAddr>>) -> Self {
+        Self {
+            hosts: Arc::new(hosts),
+        }
+    }
+
+    /// Every address `host` resolves to
+    pub(crate) async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        // Ipv6 hosts are in brackets in uris
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse() {
+            return Ok(vec![ip]);
+        }
+
+        if let Some(ips) = self.hosts.get(host) {
+            return Ok(ips.clone());
+        }
+
+        Ok(tokio::net::lookup_host((host, 0))
+            .await?
+            .map(|addr| addr.ip())
+            .collect())
+    }
+}
+
+/// Connects to the first of `addrs` to accept, starting a new attempt every
+/// [`CONNECTION_ATTEMPT_DELAY`] or when one fails, alternating between ipv6 and ipv4 (Happy Eyeballs)
+pub(crate) async fn connect(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut addrs = interleave_families(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.spawn(TcpStream::connect(addr));
+        }
+
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "No addresses to connect to")
+            }));
+        }
+
+        // Wait for an attempt to finish, moving on to the next address once one fails or the delay passes.
+        // Attempts still in flight are aborted once the `JoinSet` is dropped
+        select! {
+            Some(res) = attempts.join_next() => match res {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_err = Some(err),
+                Err(err) => last_err = Some(io::Error::other(err)),
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if addrs.len() > 0 => {}
+        }
+    }
+}
+
+/// Orders `addrs` alternating between address families, starting with the family of the first address
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (mut preferred, mut other) = addrs
+        .iter()
+        .copied()
+        .partition::<Vec<_>, _>(|addr| addr.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    while let Some(addr) = preferred.pop() {
+        interleaved.push(addr);
+        interleaved.extend(other.pop());
+    }
+    interleaved.extend(other.into_iter().rev());
+
+    interleaved
+}
+
+/// Spawns a task for each upstream with `resolve` set, which stops once `upstreams` is dropped
+pub(crate) fn spawn_resolvers(upstreams: &Arc<Upstreams>, resolver: &Resolver) {
+    for (key, (upstream, _)) in upstreams.iter().enumerate() {
+        let Some(resolve) = &upstream.resolve else {
+            continue;
+        };
+        let upstreams = Arc::downgrade(upstreams);
+
+        tokio::spawn(run_resolver(
+            upstreams,
+            key,
+            resolve.interval,
+            resolver.clone(),
+        ));
+    }
+}
+
+async fn run_resolver(upstreams: Weak<Upstreams>, key: usize, every: Duration, resolver: Resolver) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+
+        let Some(hosts) = upstreams.upgrade().map(|upstreams| {
+            upstreams[key]
+                .0
+                .all_servers()
+                .filter_map(|server| match server.addr {
+                    // Ip addresses don't need resolving
+                    UpstreamAddr::Uri(uri) => uri
+                        .host()
+                        .filter(|host| host.trim_matches(['[', ']']).parse::<IpAddr>().is_err())
+                        .map(str::to_owned),
+                    UpstreamAddr::Unix(_) => None,
+                })
+                .collect::<Vec<_>>()
+        }) else {
+            return;
+        };
+
+        let mut resolved = HashMap::new();
+        for host in hosts {
+            match resolver.resolve(&host).await {
+                Ok(ips) if !ips.is_empty() => {
+                    resolved.insert(host, ips);
+                }
+                // Keep using the addresses from the last time it resolved
+                Ok(_) => {
+                    cfg_logging! {warn!("Upstream host {host} resolved to no addresses");}
+                }
+                Err(_err) => {
+                    cfg_logging! {warn!("Failed to resolve upstream host {host}: {_err}");}
+                }
+            }
+        }
+
+        let Some(upstreams) = upstreams.upgrade() else {
+            return;
+        };
+        let (upstream, balancer) = &upstreams[key];
+        if let Err(_err) = balancer.set_resolved(upstream, &resolved) {
+            cfg_logging! {error!("Failed to update upstream servers: {_err}");}
+        }
+    }
+}
@@ -1,18 +1,31 @@
-use std::{fs, sync::Arc};
+use std::{fs, sync::Arc, time::Duration};
 
 use bytes::Bytes;
 use http::{
     header::{CONNECTION, UPGRADE},
-    Request, Response, StatusCode,
+    HeaderName, HeaderValue, Request, Response, StatusCode,
 };
 use http_body_util::{BodyExt, Empty};
 use hyper::client;
 use hyper_util::rt::TokioIo;
 use maplit::hashmap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use regex::Regex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    task::JoinSet,
+};
 use utils::{start_rule, CertKeyFiles, TestUpstream};
 
-use crate::{config::Tls, tcp_connect, Config, Server};
+use crate::{
+    config::{
+        authentication::{Authentication, AuthenticationSource},
+        match_type::MatchType,
+        CircuitBreaker, Dynamic, GroupRule, HashKey, HeaderOps, HealthCheck, LoadBalance,
+        OutlierDetection, Pool, ProxyProtocol, Resolve, Retry, Rewrite, RuleGroup, RuleSettings,
+        StickyCookie, Timeouts, Tls, Upstream, UpstreamProtocol, UpstreamServer, UpstreamTls,
+    },
+    tcp_connect, Body, CacheSettings, Config, Handler, MotorxService, Rule, Server,
+};
 
 mod utils;
 
@@ -219,6 +232,2649 @@ async fn remove_match() {
     assert_eq!(req.uri().path(), "/");
 }
 
+#[tokio::test]
+async fn http2_upstream() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http2(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                protocol: UpstreamProtocol::Http2,
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Open the connection, then send requests at once so they have to share it
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut requests = JoinSet::new();
+    for i in 0..10 {
+        requests.spawn(client.get(format!("{server_uri}/{i}")).send());
+    }
+
+    while let Some(res) = requests.join_next().await {
+        assert_eq!(res.unwrap().unwrap().status(), StatusCode::OK);
+    }
+
+    let requests = upstream.requests_received().await;
+    assert_eq!(requests.len(), 11);
+    assert!(requests
+        .iter()
+        .all(|req| req.version() == http::Version::HTTP_2));
+    assert_eq!(upstream.connections_accepted(), 1);
+}
+
+#[tokio::test]
+async fn tls_upstream_alpn_http2() {
+    utils::tracing();
+    let cert_key_files = utils::gen_self_signed();
+
+    let mut upstream = TestUpstream::new_https(
+        |_| async move { Response::builder().body(Empty::new().boxed()).unwrap() },
+        utils::tls_server_config(&cert_key_files, &[b"h2", b"http/1.1"]),
+    )
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                protocol: UpstreamProtocol::Auto,
+                tls: Some(UpstreamTls {
+                    ca_file: Some(cert_key_files.cert_file.path().into()),
+                    system_roots: false,
+                    sni: Some("localhost".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client.get(server_uri).send().await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let requests = upstream.requests_received().await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].version(), http::Version::HTTP_2);
+}
+
+#[tokio::test]
+async fn tls_upstream() {
+    utils::tracing();
+    let cert_key_files = utils::gen_self_signed();
+
+    let mut upstream = TestUpstream::new_https(
+        |_| async move { Response::builder().body(Empty::new().boxed()).unwrap() },
+        utils::tls_server_config(&cert_key_files, &[b"http/1.1"]),
+    )
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                tls: Some(UpstreamTls {
+                    ca_file: Some(cert_key_files.cert_file.path().into()),
+                    system_roots: false,
+                    // Certificate is for localhost, but we connect to 127.0.0.1
+                    sni: Some("localhost".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client.get(server_uri).send().await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(upstream.requests_received().await.len(), 1);
+}
+
+#[tokio::test]
+async fn tls_upstream_untrusted() {
+    utils::tracing();
+    let cert_key_files = utils::gen_self_signed();
+
+    let mut upstream = TestUpstream::new_https(
+        |_| async move { Response::builder().body(Empty::new().boxed()).unwrap() },
+        utils::tls_server_config(&cert_key_files, &[b"http/1.1"]),
+    )
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => upstream.as_upstream()
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Self signed certificate isn't trusted
+    let res = client.get(server_uri).send().await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(upstream.requests_received().await.len(), 0);
+}
+
+#[tokio::test]
+async fn unix_socket_upstream() {
+    utils::tracing();
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut upstream = TestUpstream::new_unix(
+        |_| async move { Response::builder().body(Empty::new().boxed()).unwrap() },
+        &dir.path().join("upstream.sock"),
+    )
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => upstream.as_upstream()
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let requests = upstream.requests_received().await;
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].headers().get("host").unwrap(), "localhost");
+    // Both requests went over the pooled connection
+    assert_eq!(upstream.connections_accepted(), 1);
+}
+
+#[tokio::test]
+async fn resolve_hostname_to_servers() {
+    utils::tracing();
+
+    let mut upstream_a = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let port = upstream_a.uri().port_u16().unwrap();
+    // Same port on another loopback address, as if both were behind one hostname
+    let mut upstream_b = TestUpstream::new_http1_on(
+        |_| async move { Response::builder().body(Empty::new().boxed()).unwrap() },
+        format!("127.0.0.2:{port}").parse().unwrap(),
+    )
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream_a.id().to_string() => Arc::new(Upstream {
+                addr: Some(format!("http://app.test:{port}").parse().unwrap()),
+                resolve: Some(Resolve {
+                    interval: Duration::from_millis(50),
+                }),
+                ..Default::default()
+            })
+        },
+        hosts: hashmap! {
+            "app.test".into() => vec!["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()]
+        },
+        rules: vec![start_rule("/", &upstream_a, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Give the hostname time to resolve
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..4 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let requests_a = upstream_a.requests_received().await;
+    let requests_b = upstream_b.requests_received().await;
+    assert_eq!(requests_a.len(), 2);
+    assert_eq!(requests_b.len(), 2);
+    assert_eq!(
+        requests_a[0].headers().get("host").unwrap(),
+        &format!("app.test:{port}")
+    );
+}
+
+#[tokio::test]
+async fn connect_falls_back_to_next_address() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let port = upstream.uri().port_u16().unwrap();
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(format!("http://app.test:{port}").parse().unwrap()),
+                ..Default::default()
+            })
+        },
+        // Nothing is listening on the first address
+        hosts: hashmap! {
+            "app.test".into() => vec!["127.0.0.3".parse().unwrap(), "127.0.0.1".parse().unwrap()]
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client.get(server_uri).send().await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(upstream.requests_received().await.len(), 1);
+}
+
+#[tokio::test]
+async fn upstream_base_path() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(format!("{}service-a/", upstream.uri()).parse().unwrap()),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/api", &upstream, true)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for path in ["/api/items?page=2&sort=asc", "/api", "/api/"] {
+        let res = client
+            .get(format!("{server_uri}{path}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let requests = upstream.requests_received().await;
+    let uris = requests
+        .iter()
+        .map(|req| req.uri().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        uris,
+        [
+            "/service-a/items?page=2&sort=asc",
+            "/service-a/",
+            "/service-a/"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn header_ops() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .header("server", "test-upstream")
+            .header("x-powered-by", "test")
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                request_headers: HeaderOps {
+                    set: vec![
+                        ("x-env".parse().unwrap(), "prod".parse().unwrap()),
+                        ("authorization".parse().unwrap(), "Bearer static".parse().unwrap()),
+                    ],
+                    ..Default::default()
+                },
+                response_headers: HeaderOps {
+                    remove: vec!["server".parse().unwrap()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        },
+        rules: vec![Rule {
+            path: MatchType::Regex(Regex::new(r"^/users/(?P<user>\w+)/(\d+)").unwrap()),
+            request_headers: HeaderOps {
+                // Replaces the upstream's value
+                set: vec![("x-env".parse().unwrap(), "staging".parse().unwrap())],
+                add: vec![
+                    ("x-user".parse().unwrap(), "${user}:${2}".parse().unwrap()),
+                    (
+                        "x-client".parse().unwrap(),
+                        "ip=${client_ip}".parse().unwrap(),
+                    ),
+                    ("x-trace".parse().unwrap(), "${request_id}".parse().unwrap()),
+                ],
+                remove: vec!["x-secret".parse().unwrap()],
+            },
+            response_headers: HeaderOps {
+                add: vec![("x-powered-by".parse().unwrap(), "motorx".parse().unwrap())],
+                ..Default::default()
+            },
+            ..start_rule("/", &upstream, false)
+        }],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client
+        .get(format!("{server_uri}/users/alice/42"))
+        .header("x-secret", "hunter2")
+        .header("x-request-id", "abc-123")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("server").is_none());
+    let powered_by = res
+        .headers()
+        .get_all("x-powered-by")
+        .iter()
+        .collect::<Vec<_>>();
+    assert_eq!(powered_by, ["test", "motorx"]);
+
+    let requests = upstream.requests_received().await;
+    let headers = requests[0].headers();
+    assert_eq!(headers["x-env"], "staging");
+    assert_eq!(headers["authorization"], "Bearer static");
+    assert_eq!(headers["x-user"], "alice:42");
+    assert_eq!(headers["x-client"], "ip=127.0.0.1");
+    assert_eq!(headers["x-trace"], "abc-123");
+    assert!(headers.get("x-secret").is_none());
+    // `Host` is replaced once the server is picked, so changing it is a config error
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! { upstream.id().to_string() => upstream.as_upstream() },
+        rules: vec![Rule {
+            request_headers: HeaderOps {
+                set: vec![(http::header::HOST, "example.com".parse().unwrap())],
+                ..Default::default()
+            },
+            ..start_rule("/", &upstream, false)
+        }],
+        ..Default::default()
+    };
+    assert!(matches!(Server::new(config), Err(crate::Error::Config(_))));
+}
+
+#[tokio::test]
+async fn trusted_proxies() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let mut servers = Vec::new();
+    for trusted_proxies in [
+        vec![],
+        vec![
+            "127.0.0.1/32".parse().unwrap(),
+            "10.0.0.0/8".parse().unwrap(),
+        ],
+    ] {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            upstreams: hashmap! {
+                upstream.id().to_string() => upstream.as_upstream()
+            },
+            rules: vec![start_rule("/", &upstream, false)],
+            trusted_proxies,
+            ..Default::default()
+        };
+        let server = Server::new(config).unwrap();
+        servers.push(format!("http://{}", server.local_addr().unwrap()));
+        tokio::spawn(async move {
+            server.run().await.unwrap();
+        });
+    }
+    let client = utils::client();
+
+    for server_uri in &servers {
+        let res = client
+            .get(server_uri)
+            .header("x-forwarded-for", "203.0.113.7, 198.51.100.1, 10.0.0.2")
+            .header("x-forwarded-proto", "https")
+            .header("x-real-ip", "6.6.6.6")
+            .header("forwarded", "for=10.0.0.2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let requests = upstream.requests_received().await;
+
+    // Headers from an untrusted client are replaced
+    let headers = requests[0].headers();
+    assert_eq!(headers["x-forwarded-for"], "127.0.0.1");
+    assert_eq!(headers["x-real-ip"], "127.0.0.1");
+    assert_eq!(headers["x-forwarded-proto"], "http");
+    assert_eq!(
+        headers["x-forwarded-host"],
+        servers[0].trim_start_matches("http://")
+    );
+    let forwarded = headers.get_all("forwarded").iter().collect::<Vec<_>>();
+    assert_eq!(forwarded.len(), 1);
+    assert!(forwarded[0]
+        .to_str()
+        .unwrap()
+        .starts_with("for=127.0.0.1;proto=http;host="));
+
+    // The chain of trusted proxies is followed back to the first untrusted address
+    let headers = requests[1].headers();
+    assert_eq!(
+        headers["x-forwarded-for"],
+        "203.0.113.7, 198.51.100.1, 10.0.0.2, 127.0.0.1"
+    );
+    assert_eq!(headers["x-real-ip"], "198.51.100.1");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+    assert_eq!(headers.get_all("forwarded").iter().count(), 2);
+
+    // Http2 requests have their host in the uri's authority
+    let res = utils::http2_client().get(&servers[0]).send().await.unwrap();
+    assert_eq!(res.version(), http::Version::HTTP_2);
+    let requests = upstream.requests_received().await;
+    let headers = requests[0].headers();
+    assert_eq!(
+        headers["x-forwarded-host"],
+        servers[0].trim_start_matches("http://")
+    );
+    assert!(headers["forwarded"].to_str().unwrap().ends_with(&format!(
+        r#";host="{}""#,
+        servers[0].trim_start_matches("http://")
+    )));
+}
+
+#[tokio::test]
+async fn preserve_host() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                preserve_host: true,
+                authentication: Some(Authentication {
+                    exclude: Vec::new(),
+                    source: AuthenticationSource::Path("/auth".into()),
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![
+            Rule {
+                host: Some(HeaderValue::from_static("fixed.internal")),
+                ..start_rule("/fixed", &upstream, false)
+            },
+            Rule {
+                preserve_host: Some(false),
+                ..start_rule("/plain", &upstream, false)
+            },
+            start_rule("/", &upstream, false),
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for path in ["/tenant", "/fixed", "/plain"] {
+        let res = client
+            .get(format!("{server_uri}{path}"))
+            .header("host", "tenant-a.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    // Each request is preceded by its authentication request, which gets the same host
+    let requests = upstream.requests_received().await;
+    let hosts = requests
+        .iter()
+        .map(|req| (req.uri().path(), req.headers()["host"].to_str().unwrap()))
+        .collect::<Vec<_>>();
+    let upstream_host = upstream.uri().authority().unwrap().to_string();
+    assert_eq!(
+        hosts,
+        [
+            ("/auth", "tenant-a.example.com"),
+            ("/tenant", "tenant-a.example.com"),
+            ("/auth", "fixed.internal"),
+            ("/fixed", "fixed.internal"),
+            ("/auth", upstream_host.as_str()),
+            ("/plain", upstream_host.as_str()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn request_id() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let mut servers = Vec::new();
+    for request_id_header in [Some(HeaderName::from_static("x-request-id")), None] {
+        let config = Config {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            upstreams: hashmap! {
+                upstream.id().to_string() => upstream.as_upstream()
+            },
+            rules: vec![start_rule("/", &upstream, false)],
+            request_id_header,
+            ..Default::default()
+        };
+        let server = Server::new(config).unwrap();
+        servers.push(format!("http://{}", server.local_addr().unwrap()));
+        tokio::spawn(async move {
+            server.run().await.unwrap();
+        });
+    }
+    let client = utils::client();
+
+    // A generated id is sent upstream and back to the client
+    let res = client.get(&servers[0]).send().await.unwrap();
+    let generated = res.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_eq!(generated.len(), 36);
+    assert_eq!(&generated[14..15], "7");
+
+    // The client's id is kept, unless it is unusable
+    let res = client
+        .get(&servers[0])
+        .header("x-request-id", "abc-123")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["x-request-id"], "abc-123");
+    let res = client
+        .get(&servers[0])
+        .header("x-request-id", "a".repeat(200))
+        .send()
+        .await
+        .unwrap();
+    let replaced = res.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_eq!(replaced.len(), 36);
+    assert_ne!(replaced, generated);
+
+    let res = client.get(&servers[1]).send().await.unwrap();
+    assert!(res.headers().get("x-request-id").is_none());
+
+    let requests = upstream.requests_received().await;
+    let ids = requests
+        .iter()
+        .map(|req| {
+            req.headers()
+                .get("x-request-id")
+                .map(|id| id.to_str().unwrap())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ids,
+        [
+            Some(generated.as_str()),
+            Some("abc-123"),
+            Some(replaced.as_str()),
+            None
+        ]
+    );
+}
+
+#[tokio::test]
+async fn hop_headers_and_via() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .header("connection", "x-upstream-hop")
+            .header("x-upstream-hop", "1")
+            .header("proxy-authenticate", "Basic")
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => upstream.as_upstream()
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        proxy_name: "edge-1".into(),
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client
+        .get(&server_uri)
+        .header("connection", "x-client-hop")
+        .header("x-client-hop", "1")
+        .header("proxy-authorization", "Basic Zm9vOmJhcg==")
+        .header("te", "trailers")
+        .header("via", "1.1 edge-0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("x-upstream-hop").is_none());
+    assert!(res.headers().get("proxy-authenticate").is_none());
+    assert_eq!(res.headers()["via"], "1.1 edge-1");
+
+    let requests = upstream.requests_received().await;
+    let headers = requests[0].headers();
+    assert!(headers.get("x-client-hop").is_none());
+    assert!(headers.get("proxy-authorization").is_none());
+    assert_eq!(headers["te"], "trailers");
+    let via = headers.get_all("via").iter().collect::<Vec<_>>();
+    assert_eq!(via, ["1.1 edge-0", "1.1 edge-1"]);
+
+    // A request which already went through this proxy is looping
+    let res = client
+        .get(&server_uri)
+        .header("via", "1.1 edge-0, 1.1 edge-1 (motorx)")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::LOOP_DETECTED);
+    assert!(upstream.requests_received().await.is_empty());
+}
+
+#[tokio::test]
+async fn rewrite_responses() {
+    utils::tracing();
+
+    let upstream = TestUpstream::new_http1(|parts| {
+        let host = parts.headers["host"].to_str().unwrap().to_string();
+        let redirect = parts.uri.path() == "/svc/redirect";
+        async move {
+            let builder = if redirect {
+                Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("location", format!("http://{host}/svc/login?next=1"))
+                    .header("set-cookie", "session=1; Path=/svc/account; HttpOnly")
+                    .header("set-cookie", "pref=dark; Path=/; Domain=127.0.0.1")
+                    .header("set-cookie", "theme=x; Domain=.app.internal")
+            } else {
+                Response::builder()
+                    .status(StatusCode::SEE_OTHER)
+                    .header("location", "/svc")
+                    .header("content-location", "/other")
+                    .header("set-cookie", "session=2; Path=/svc")
+            };
+            builder.body(Empty::new().boxed()).unwrap()
+        }
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(format!("{}svc", upstream.uri()).parse().unwrap()),
+                ..Default::default()
+            })
+        },
+        rules: vec![
+            Rule {
+                rewrite: Rewrite {
+                    location: true,
+                    cookie_path: true,
+                    cookie_domains: hashmap! { "app.internal".into() => "example.com".into() },
+                    remove_cookie_domain: true,
+                },
+                ..start_rule("/app", &upstream, true)
+            },
+            start_rule("/plain", &upstream, true),
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_authority = server.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::base_client()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let res = client
+        .get(format!("http://{server_authority}/app/redirect"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(
+        res.headers()["location"],
+        format!("http://{server_authority}/app/login?next=1").as_str()
+    );
+    let cookies = res
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cookies,
+        [
+            "session=1; Path=/app/account; HttpOnly",
+            // Outside the server's path, and the server's domain is left to the client
+            "pref=dark; Path=/",
+            "theme=x; Domain=example.com"
+        ]
+    );
+
+    let res = client
+        .get(format!("http://{server_authority}/app"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["location"], "/app/");
+    assert_eq!(res.headers()["content-location"], "/other");
+    assert_eq!(res.headers()["set-cookie"], "session=2; Path=/app");
+
+    // Nothing is rewritten unless asked for
+    let res = client
+        .get(format!("http://{server_authority}/plain/redirect"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.headers()["location"],
+        format!(
+            "http://{}/svc/login?next=1",
+            upstream.uri().authority().unwrap()
+        )
+        .as_str()
+    );
+    let cookies = res
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cookies,
+        [
+            "session=1; Path=/svc/account; HttpOnly",
+            "pref=dark; Path=/; Domain=127.0.0.1",
+            "theme=x; Domain=.app.internal"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn dynamic_upstream() {
+    utils::tracing();
+
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(
+            TestUpstream::new_http1(|_| async move {
+                Response::builder().body(Empty::new().boxed()).unwrap()
+            })
+            .await,
+        );
+    }
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            "dynamic".into() => Arc::new(Upstream {
+                dynamic: Some(Dynamic {
+                    max_servers: 2,
+                    idle_timeout: Duration::from_millis(300),
+                    ..Dynamic::new("http://127.0.0.1:${port}".parse().unwrap())
+                }),
+                ..Default::default()
+            }),
+            "by-host".into() => Arc::new(Upstream {
+                dynamic: Some(Dynamic::new("http://127.0.0.1:${port}".parse().unwrap())),
+                ..Default::default()
+            }),
+        },
+        rules: vec![
+            Rule {
+                path: MatchType::Regex(Regex::new(r"^/port/(?P<port>\d{1,5})$").unwrap()),
+                upstream: "dynamic".into(),
+                ..start_rule("/", &upstreams[0], false)
+            },
+            Rule {
+                match_headers: Some(hashmap! {
+                    "host".into() => MatchType::Regex(
+                        Regex::new(r"^port-(?P<port>\d{1,5})\.example\.com(:\d+)?$").unwrap(),
+                    ),
+                }),
+                upstream: "by-host".into(),
+                ..start_rule("/host", &upstreams[0], false)
+            },
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let server_uri = format!("http://{server_addr}");
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let get = |i: usize| {
+        let port = upstreams[i].uri().port_u16().unwrap();
+        client.get(format!("{server_uri}/port/{port}")).send()
+    };
+
+    // Servers are created as requests for them come in, and reused after that
+    for i in [0, 1, 1] {
+        assert_eq!(get(i).await.unwrap().status(), StatusCode::OK);
+    }
+    assert_eq!(upstreams[0].connections_accepted(), 1);
+    assert_eq!(upstreams[1].connections_accepted(), 1);
+
+    // The least recently used server is dropped to make room, so the next request to it needs a new connection
+    assert_eq!(get(2).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(get(1).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(upstreams[1].connections_accepted(), 1);
+    assert_eq!(get(0).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(upstreams[0].connections_accepted(), 2);
+
+    // Idle servers are dropped
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(get(1).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(upstreams[1].connections_accepted(), 2);
+
+    for upstream in &mut upstreams {
+        assert!(upstream
+            .requests_received()
+            .await
+            .iter()
+            .all(|req| req.uri().path().starts_with("/port/")));
+    }
+
+    // Captures from the host, which http2 requests only have in the uri's authority
+    let port = upstreams[2].uri().port_u16().unwrap();
+    let host = format!("port-{port}.example.com");
+    let http2_client = utils::base_client()
+        .http2_prior_knowledge()
+        .resolve(&host, server_addr)
+        .build()
+        .unwrap();
+    let res = http2_client
+        .get(format!("http://{host}:{}/host", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.version(), http::Version::HTTP_2);
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(upstreams[2].requests_received().await.len(), 1);
+}
+
+#[tokio::test]
+async fn dynamic_upstream_tls() {
+    utils::tracing();
+    let cert_key_files = utils::gen_self_signed();
+
+    let mut tls_upstream = TestUpstream::new_https(
+        |_| async move { Response::builder().body(Empty::new().boxed()).unwrap() },
+        utils::tls_server_config(&cert_key_files, &[b"http/1.1"]),
+    )
+    .await;
+    let mut plain_upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            "https".into() => Arc::new(Upstream {
+                dynamic: Some(Dynamic::new("https://127.0.0.1:${port}".parse().unwrap())),
+                tls: Some(UpstreamTls {
+                    ca_file: Some(cert_key_files.cert_file.path().into()),
+                    system_roots: false,
+                    sni: Some("localhost".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            "any".into() => Arc::new(Upstream {
+                dynamic: Some(Dynamic::new("${scheme}://127.0.0.1:${port}".parse().unwrap())),
+                ..Default::default()
+            })
+        },
+        rules: vec![
+            Rule {
+                path: MatchType::Regex(Regex::new(r"^/tls/(?P<port>\d{1,5})$").unwrap()),
+                upstream: "https".into(),
+                ..start_rule("/", &tls_upstream, false)
+            },
+            Rule {
+                path: MatchType::Regex(
+                    Regex::new(r"^/any/(?P<scheme>https?)/(?P<port>\d{1,5})$").unwrap(),
+                ),
+                upstream: "any".into(),
+                ..start_rule("/", &tls_upstream, false)
+            },
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let tls_port = tls_upstream.uri().port_u16().unwrap();
+    let res = client
+        .get(format!("{server_uri}/tls/{tls_port}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(tls_upstream.requests_received().await.len(), 1);
+
+    // Only the request knows it's https, with no tls set up it isn't sent as plain http instead
+    let plain_port = plain_upstream.uri().port_u16().unwrap();
+    let res = client
+        .get(format!("{server_uri}/any/http/{plain_port}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client
+        .get(format!("{server_uri}/any/https/{plain_port}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(plain_upstream.requests_received().await.len(), 1);
+}
+
+#[tokio::test]
+async fn rule_groups() {
+    utils::tracing();
+
+    let mut api = TestUpstream::new_http1(|parts| {
+        let denied = parts.uri.path() == "/auth" && !parts.headers.contains_key("x-token");
+        async move {
+            let status = if denied {
+                StatusCode::UNAUTHORIZED
+            } else {
+                StatusCode::OK
+            };
+            Response::builder()
+                .status(status)
+                .body(Empty::new().boxed())
+                .unwrap()
+        }
+    })
+    .await;
+    let mut admin = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let set = |name: &str, value: &str| HeaderOps {
+        set: vec![(name.parse().unwrap(), value.parse().unwrap())],
+        ..Default::default()
+    };
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            api.id().to_string() => api.as_upstream(),
+            admin.id().to_string() => admin.as_upstream(),
+        },
+        groups: vec![
+            RuleGroup {
+                prefix: "/api".into(),
+                settings: RuleSettings {
+                    upstream: Some(api.id().to_string()),
+                    remove_match: Some(true),
+                    request_headers: set("x-group", "api"),
+                    ..Default::default()
+                },
+                rules: vec![
+                    GroupRule {
+                        path: Some(MatchType::Start("/users".into())),
+                        ..Default::default()
+                    },
+                    GroupRule {
+                        path: Some(MatchType::Start("/admin".into())),
+                        settings: RuleSettings {
+                            upstream: Some(admin.id().to_string()),
+                            request_headers: set("x-group", "admin"),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    GroupRule {
+                        path: Some(MatchType::Regex(
+                            Regex::new(r"^/items/(?P<id>\d+)$").unwrap(),
+                        )),
+                        settings: RuleSettings {
+                            request_headers: set("x-item", "${id}"),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                ],
+                groups: vec![RuleGroup {
+                    prefix: "/v2".into(),
+                    match_headers: Some(
+                        hashmap! { "x-version".into() => MatchType::Start("2".into()) },
+                    ),
+                    rules: vec![GroupRule::default()],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            RuleGroup {
+                prefix: "/private".into(),
+                settings: RuleSettings {
+                    upstream: Some(api.id().to_string()),
+                    authentication: Some(Authentication {
+                        exclude: Vec::new(),
+                        source: AuthenticationSource::Path("/auth".into()),
+                    }),
+                    ..Default::default()
+                },
+                rules: vec![
+                    GroupRule::default(),
+                    GroupRule {
+                        path: Some(MatchType::Start("/open".into())),
+                        settings: RuleSettings {
+                            authentication: Some(Authentication {
+                                exclude: Vec::new(),
+                                source: AuthenticationSource::Path("/open-auth".into()),
+                            }),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            },
+            RuleGroup {
+                match_host: Some(MatchType::Start("admin.example.com".into())),
+                settings: RuleSettings {
+                    upstream: Some(admin.id().to_string()),
+                    ..Default::default()
+                },
+                rules: vec![GroupRule::default()],
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let server_uri = format!("http://{server_addr}");
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let get = |path: &str| client.get(format!("{server_uri}{path}"));
+
+    // Settings are inherited from the group
+    let res = get("/api/users/1").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let req = api.requests_received().await.remove(0);
+    assert_eq!(req.uri().path(), "/1");
+    assert_eq!(req.headers()["x-group"], "api");
+
+    // and can be overridden by rules
+    let res = get("/api/admin").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let req = admin.requests_received().await.remove(0);
+    assert_eq!(req.uri().path(), "/");
+    assert_eq!(req.headers()["x-group"], "admin");
+
+    // Regex paths are matched after the prefix
+    let res = get("/api/items/42").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let req = api.requests_received().await.remove(0);
+    assert_eq!(req.uri().path(), "/api/items/42");
+    assert_eq!(req.headers()["x-item"], "42");
+    assert_eq!(
+        get("/items/42").send().await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+
+    // Nested groups add to the prefix and match conditions
+    let res = get("/api/v2/users")
+        .header("x-version", "2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let req = api.requests_received().await.remove(0);
+    assert_eq!(req.uri().path(), "/users");
+    assert_eq!(req.headers()["x-group"], "api");
+    assert_eq!(
+        get("/api/v2/users").send().await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let res = get("/anything")
+        .header("host", "admin.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(admin.requests_received().await.len(), 1);
+    assert_eq!(
+        get("/anything").send().await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+
+    // Http2 requests only have the host in their `:authority`
+    let http2_client = utils::base_client()
+        .http2_prior_knowledge()
+        .resolve("admin.example.com", server_addr)
+        .build()
+        .unwrap();
+    let res = http2_client
+        .get(format!(
+            "http://admin.example.com:{}/anything",
+            server_addr.port()
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.version(), http::Version::HTTP_2);
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(admin.requests_received().await.len(), 1);
+
+    // Authentication is inherited from the group
+    let res = get("/private/data").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let requests = api.requests_received().await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].uri().path(), "/auth");
+    let res = get("/private/data")
+        .header("x-token", "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(api.requests_received().await.len(), 2);
+
+    // and can be overridden by rules
+    let res = get("/private/open").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let requests = api.requests_received().await;
+    assert_eq!(requests[0].uri().path(), "/open-auth");
+    assert_eq!(requests[1].uri().path(), "/private/open");
+
+    // Rules must get an upstream from somewhere
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! { api.id().to_string() => api.as_upstream() },
+        groups: vec![RuleGroup {
+            prefix: "/api".into(),
+            rules: vec![GroupRule::default()],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(matches!(Server::new(config), Err(crate::Error::Config(_))));
+}
+
+/// Service for `handlers`, which fails for paths other than `/`
+#[derive(Clone)]
+struct PathService;
+
+impl tower::Service<Request<crate::Body>> for PathService {
+    type Response = Response<http_body_util::Full<Bytes>>;
+    type Error = std::io::Error;
+    type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<crate::Body>) -> Self::Future {
+        std::future::ready(match req.uri().path() {
+            "/" => Ok(Response::new(Bytes::from("service").into())),
+            _ => Err(std::io::Error::other("not found")),
+        })
+    }
+}
+
+#[tokio::test]
+async fn handlers() {
+    utils::tracing();
+
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let health = Handler::from_fn({
+        let calls = Arc::clone(&calls);
+        move |req| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let body = format!(
+                "{} {}",
+                req.uri().path(),
+                req.headers()["x-rule"].to_str().unwrap()
+            );
+            async move { Response::new(http_body_util::Full::new(Bytes::from(body))) }
+        }
+    });
+
+    let rule = |path: &str, upstream: &str| Rule {
+        path: MatchType::Start(path.into()),
+        remove_match: true,
+        match_headers: None,
+        upstream: upstream.into(),
+        cache: None,
+        retry: None,
+        timeouts: None,
+        authentication: None,
+        request_headers: Default::default(),
+        response_headers: Default::default(),
+        preserve_host: None,
+        host: None,
+        rewrite: Default::default(),
+        cache_key: 0,
+        upstream_key: 0,
+        handler: None,
+    };
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        handlers: hashmap! {
+            "health".into() => health,
+            "service".into() => Handler::from_service(PathService),
+        },
+        rules: vec![
+            Rule {
+                cache: Some(CacheSettings {
+                    methods: vec![http::Method::GET],
+                    max_age: Duration::from_secs(60),
+                }),
+                request_headers: HeaderOps {
+                    set: vec![("x-rule".parse().unwrap(), "${request_id}".parse().unwrap())],
+                    ..Default::default()
+                },
+                response_headers: HeaderOps {
+                    set: vec![("x-handler".parse().unwrap(), "health".parse().unwrap())],
+                    ..Default::default()
+                },
+                ..rule("/health", "health")
+            },
+            rule("/service", "service"),
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Handlers get the request after `remove_match` and the rule's header changes, and are cached
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let res = client
+            .get(format!("{server_uri}/health/live"))
+            .header("x-request-id", "first")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-handler"], "health");
+        assert!(res.headers().get("via").is_none());
+        bodies.push(res.text().await.unwrap());
+    }
+    assert_eq!(bodies, ["/live first", "/live first"]);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+    let res = client
+        .get(format!("{server_uri}/service"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), "service");
+    let res = client
+        .get(format!("{server_uri}/service/missing"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // Names are shared between upstreams and handlers
+    let upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! { "health".into() => upstream.as_upstream() },
+        handlers: hashmap! { "health".into() => Handler::from_service(PathService) },
+        rules: vec![rule("/", "health")],
+        ..Default::default()
+    };
+    assert!(matches!(Server::new(config), Err(crate::Error::Config(_))));
+
+    // Handlers don't authenticate requests
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        handlers: hashmap! { "health".into() => Handler::from_service(PathService) },
+        rules: vec![Rule {
+            authentication: Some(Authentication {
+                exclude: Vec::new(),
+                source: AuthenticationSource::Path("/auth".into()),
+            }),
+            ..rule("/", "health")
+        }],
+        ..Default::default()
+    };
+    assert!(matches!(Server::new(config), Err(crate::Error::Config(_))));
+}
+
+/// Body which isn't `Sync`, like axum's
+struct UnsyncBody(
+    http_body_util::Full<Bytes>,
+    std::marker::PhantomData<std::cell::Cell<()>>,
+);
+
+impl http_body::Body for UnsyncBody {
+    type Data = Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<http_body::Frame<Bytes>, Self::Error>>> {
+        std::pin::Pin::new(&mut self.get_mut().0).poll_frame(cx)
+    }
+}
+
+#[tokio::test]
+async fn tower_service() {
+    use tower::ServiceExt;
+
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let (upstream_id, upstream_addr) = (upstream.id().to_string(), upstream.addr());
+    let rules = vec![
+        start_rule("/", &upstream, false),
+        Rule {
+            upstream: "local".into(),
+            ..start_rule("/local", &upstream, false)
+        },
+    ];
+    let config = || Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream_id.clone() => Arc::new(Upstream {
+                addr: Some(upstream_addr.clone()),
+                ..Default::default()
+            }),
+        },
+        handlers: hashmap! {
+            "local".into() => Handler::from_fn(|_| async { Response::new(Empty::<Bytes>::new()) }),
+        },
+        rules: rules.clone(),
+        ..Default::default()
+    };
+
+    // Used on its own, with any body
+    let service = MotorxService::new(config()).unwrap();
+    let metrics = service.metrics();
+    let mut req = Request::post("/echo")
+        .body(UnsyncBody(
+            Bytes::from("hello").into(),
+            std::marker::PhantomData,
+        ))
+        .unwrap();
+    req.extensions_mut()
+        .insert(std::net::SocketAddr::from(([10, 1, 2, 3], 5000)));
+    let res = service.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key("x-request-id"));
+    let req = upstream.requests_received().await.remove(0);
+    assert_eq!(req.headers()["x-real-ip"], "10.1.2.3");
+    assert_eq!(req.body(), "hello");
+    assert_eq!(
+        metrics.upstreams()[&upstream_id],
+        crate::metrics::UpstreamMetrics::default()
+    );
+
+    // With layers around routing and upstream calls
+    let upstream_calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let service = MotorxService::builder(config())
+        .route_layer(tower::util::MapResponseLayer::new(
+            |mut res: Response<Body>| {
+                res.headers_mut()
+                    .insert("x-route-layer", HeaderValue::from_static("1"));
+                res
+            },
+        ))
+        .upstream_layer(tower::util::MapRequestLayer::new({
+            let upstream_calls = Arc::clone(&upstream_calls);
+            move |mut req: Request<Body>| {
+                upstream_calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                req.headers_mut()
+                    .insert("x-upstream-layer", HeaderValue::from_static("1"));
+                req
+            }
+        }))
+        .build()
+        .unwrap();
+    let server = Server::from_service(service).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client.get(format!("{server_uri}/")).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["x-route-layer"], "1");
+    let req = upstream.requests_received().await.remove(0);
+    assert_eq!(req.headers()["x-upstream-layer"], "1");
+
+    // Handlers aren't upstream calls
+    let res = client
+        .get(format!("{server_uri}/local"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["x-route-layer"], "1");
+    assert_eq!(upstream_calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+    // Failed upstream calls are bad gateways
+    let service = MotorxService::builder(config())
+        .upstream_layer(tower::layer::layer_fn(|_inner| {
+            tower::service_fn(|_req: Request<Body>| async {
+                Err::<Response<Body>, _>(std::io::Error::other("refused"))
+            })
+        }))
+        .build()
+        .unwrap();
+    let res = service
+        .oneshot(Request::get("/").body(Empty::<Bytes>::new()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert!(upstream.requests_received().await.is_empty());
+}
+
+#[tokio::test]
+async fn proxy_protocol() {
+    utils::tracing();
+
+    let (v1_addr, mut v1_headers) = utils::proxy_protocol_upstream().await;
+    let (v2_addr, mut v2_headers) = utils::proxy_protocol_upstream().await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            "v1".into() => Arc::new(Upstream {
+                addr: Some(v1_addr),
+                proxy_protocol: Some(ProxyProtocol::V1),
+                ..Default::default()
+            }),
+            "v2".into() => Arc::new(Upstream {
+                addr: Some(v2_addr),
+                proxy_protocol: Some(ProxyProtocol::V2),
+                max_connections: 1,
+                ..Default::default()
+            })
+        },
+        rules: ["v1", "v2"]
+            .into_iter()
+            .map(|version| Rule {
+                path: MatchType::Start(format!("/{version}")),
+                remove_match: false,
+                match_headers: None,
+                upstream: version.into(),
+                cache: None,
+                retry: None,
+                timeouts: None,
+                authentication: None,
+                request_headers: Default::default(),
+                response_headers: Default::default(),
+                preserve_host: None,
+                host: None,
+                rewrite: Default::default(),
+                cache_key: 0,
+                upstream_key: 0,
+                handler: None,
+            })
+            .collect(),
+        trusted_proxies: vec!["127.0.0.1/32".parse().unwrap()],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let server_uri = format!("http://{server_addr}");
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Connections are reused for the client their header names
+    for _ in 0..2 {
+        let res = client.get(format!("{server_uri}/v1")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let header = String::from_utf8(v1_headers.recv().await.unwrap()).unwrap();
+    assert!(header.starts_with("PROXY TCP4 127.0.0.1 127.0.0.1 "));
+    assert!(header.ends_with(&format!(" {}\r\n", server_addr.port())));
+
+    // but another client gets its own
+    let res = utils::client()
+        .get(format!("{server_uri}/v1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let other_header = String::from_utf8(v1_headers.recv().await.unwrap()).unwrap();
+    assert_ne!(header, other_header);
+
+    // The client behind a trusted proxy is named, whichever connection it came through
+    for client in [utils::client(), utils::client()] {
+        let res = client
+            .get(format!("{server_uri}/v1"))
+            .header("x-forwarded-for", "203.0.113.7")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let header = String::from_utf8(v1_headers.recv().await.unwrap()).unwrap();
+    assert_eq!(
+        header,
+        format!(
+            "PROXY TCP4 203.0.113.7 127.0.0.1 0 {}\r\n",
+            server_addr.port()
+        )
+    );
+    assert!(v1_headers.try_recv().is_err());
+
+    let res = client.get(format!("{server_uri}/v2")).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let header = v2_headers.recv().await.unwrap();
+    assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+    // PROXY command over TCP4
+    assert_eq!(header[12..16], [0x21, 0x11, 0, 12]);
+    assert_eq!(header[16..24], [127, 0, 0, 1, 127, 0, 0, 1]);
+    assert_eq!(header[26..28], server_addr.port().to_be_bytes());
+
+    // An idle connection for another client is closed to make room
+    let res = utils::client()
+        .get(format!("{server_uri}/v2"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_ne!(v2_headers.recv().await.unwrap(), header);
+}
+
+#[tokio::test]
+async fn load_balance_round_robin() {
+    utils::tracing();
+
+    let mut upstream_a = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let mut upstream_b = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream_a.id().to_string() => Arc::new(Upstream {
+                servers: vec![upstream_a.as_server(1), upstream_b.as_server(3)],
+                load_balance: LoadBalance::RoundRobin,
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream_a, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for _ in 0..8 {
+        client.get(&server_uri).send().await.unwrap();
+    }
+
+    assert_eq!(upstream_a.requests_received().await.len(), 2);
+    assert_eq!(upstream_b.requests_received().await.len(), 6);
+}
+
+#[tokio::test]
+async fn load_balance_consistent_hash() {
+    utils::tracing();
+
+    let mut upstream_a = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let mut upstream_b = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream_a.id().to_string() => Arc::new(Upstream {
+                servers: vec![upstream_a.as_server(1), upstream_b.as_server(1)],
+                load_balance: LoadBalance::ConsistentHash(HashKey::Header("x-user".into())),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream_a, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for user in ["alice", "bob", "carol", "dave"] {
+        for _ in 0..3 {
+            client
+                .get(&server_uri)
+                .header("x-user", user)
+                .send()
+                .await
+                .unwrap();
+        }
+
+        // Every request for a user should land on the same server
+        let a = upstream_a.requests_received().await.len();
+        let b = upstream_b.requests_received().await.len();
+        assert!((a, b) == (3, 0) || (a, b) == (0, 3), "{user}: {a} {b}");
+    }
+}
+
+#[tokio::test]
+async fn sticky_cookie() {
+    utils::tracing();
+
+    let mut upstream_a = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let mut upstream_b = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream_a.id().to_string() => Arc::new(Upstream {
+                servers: vec![upstream_a.as_server(1), upstream_b.as_server(1)],
+                sticky: Some(StickyCookie::default()),
+                ..Default::default()
+            })
+        },
+        rules: vec![
+            Rule {
+                cache: Some(CacheSettings {
+                    methods: vec![http::Method::GET],
+                    max_age: Duration::from_secs(60),
+                }),
+                ..start_rule("/cached", &upstream_a, false)
+            },
+            start_rule("/", &upstream_a, false),
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client.get(&server_uri).send().await.unwrap();
+    let cookie = res.headers()["set-cookie"].to_str().unwrap();
+    let cookie = cookie.split(';').next().unwrap().to_string();
+    assert!(cookie.starts_with("motorx_affinity="));
+
+    for _ in 0..4 {
+        let res = client
+            .get(&server_uri)
+            .header("cookie", &cookie)
+            .send()
+            .await
+            .unwrap();
+        // Already pinned, so the cookie isn't set again
+        assert!(res.headers().get("set-cookie").is_none());
+    }
+
+    let a = upstream_a.requests_received().await.len();
+    let b = upstream_b.requests_received().await.len();
+    assert!((a, b) == (5, 0) || (a, b) == (0, 5), "{a} {b}");
+    // Cached responses don't pin other clients to the server of the first one
+    let res = client
+        .get(format!("{server_uri}/cached"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.headers().contains_key("set-cookie"));
+    let res = client
+        .get(format!("{server_uri}/cached"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.headers().get("set-cookie").is_none());
+}
+
+#[tokio::test]
+async fn health_check() {
+    utils::tracing();
+
+    let mut healthy = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let mut unhealthy = TestUpstream::new_http1(|parts| {
+        let status = if parts.uri.path() == "/health" {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::OK
+        };
+        async move {
+            Response::builder()
+                .status(status)
+                .body(Empty::new().boxed())
+                .unwrap()
+        }
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            healthy.id().to_string() => Arc::new(Upstream {
+                servers: vec![healthy.as_server(1), unhealthy.as_server(1)],
+                health_check: Some(HealthCheck {
+                    path: "/health".into(),
+                    interval: Duration::from_millis(20),
+                    fall: 2,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &healthy, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}/app", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Wait for enough checks to fail
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..4 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let app_requests =
+        |reqs: Vec<Request<Bytes>>| reqs.iter().filter(|req| req.uri().path() == "/app").count();
+    assert_eq!(app_requests(healthy.requests_received().await), 4);
+    assert_eq!(app_requests(unhealthy.requests_received().await), 0);
+}
+
+#[tokio::test]
+async fn outlier_detection() {
+    utils::tracing();
+
+    let mut healthy = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let mut failing = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            healthy.id().to_string() => Arc::new(Upstream {
+                servers: vec![failing.as_server(1), healthy.as_server(1)],
+                outlier_detection: Some(OutlierDetection {
+                    consecutive_failures: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &healthy, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for _ in 0..6 {
+        client.get(&server_uri).send().await.unwrap();
+    }
+
+    // Ejected after its first failure
+    assert_eq!(failing.requests_received().await.len(), 1);
+    assert_eq!(healthy.requests_received().await.len(), 5);
+
+    // The only server can be ejected too
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            failing.id().to_string() => Arc::new(Upstream {
+                servers: vec![failing.as_server(1)],
+                outlier_detection: Some(OutlierDetection {
+                    consecutive_failures: Some(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &failing, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(failing.requests_received().await.len(), 1);
+}
+
+#[tokio::test]
+async fn retry_on_other_server() {
+    utils::tracing();
+
+    let mut healthy = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+    let mut failing = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            healthy.id().to_string() => Arc::new(Upstream {
+                servers: vec![failing.as_server(1), healthy.as_server(1)],
+                ..Default::default()
+            })
+        },
+        rules: vec![Rule {
+            retry: Some(Retry {
+                attempts: 2,
+                backoff: Duration::ZERO,
+                ..Default::default()
+            }),
+            ..start_rule("/", &healthy, false)
+        }],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for _ in 0..4 {
+        let res = client.put(&server_uri).body("body").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let requests = healthy.requests_received().await;
+    assert_eq!(requests.len(), 4);
+    // Body is sent again on retries
+    assert!(requests.iter().all(|req| req.body() == "body"));
+    // Round robin comes back to the failing server first each time
+    assert_eq!(failing.requests_received().await.len(), 4);
+
+    // Not idempotent, so it isn't retried once it reached the server
+    let res = client.post(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(healthy.requests_received().await.len(), 0);
+
+    // The default policy only retries failing to connect, which gives back the unsent body
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_addr = format!("http://{}", closed.local_addr().unwrap());
+    drop(closed);
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            healthy.id().to_string() => Arc::new(Upstream {
+                servers: vec![
+                    UpstreamServer {
+                        addr: closed_addr.parse().unwrap(),
+                        weight: 1,
+                        backup: false,
+                    },
+                    healthy.as_server(1),
+                ],
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &healthy, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    for _ in 0..2 {
+        let res = client.put(&server_uri).body("body").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let requests = healthy.requests_received().await;
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|req| req.body() == "body"));
+}
+
+#[tokio::test]
+async fn retry_budget() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => upstream.as_upstream()
+        },
+        rules: vec![Rule {
+            retry: Some(Retry {
+                attempts: 3,
+                backoff: Duration::ZERO,
+                budget_percent: 0,
+                min_retries_per_second: 1,
+                ..Default::default()
+            }),
+            ..start_rule("/", &upstream, false)
+        }],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for _ in 0..3 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    // Only one retry fits in the budget
+    assert_eq!(upstream.requests_received().await.len(), 4);
+}
+
+#[tokio::test]
+async fn timeouts() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|parts| {
+        let slow = parts.uri.path().ends_with("slow");
+        let trickle = parts.uri.path().ends_with("trickle");
+        async move {
+            if slow {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            if trickle {
+                let body = utils::SlowBody::new(5, Duration::from_millis(100));
+                return Response::builder().body(body.boxed()).unwrap();
+            }
+            Response::builder().body(Empty::new().boxed()).unwrap()
+        }
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                timeouts: Timeouts {
+                    response: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+                addr: Some(upstream.addr()),
+                ..Default::default()
+            }),
+            "guarded".into() => Arc::new(Upstream {
+                timeouts: Timeouts {
+                    response: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+                addr: Some(upstream.addr()),
+                authentication: Some(Authentication {
+                    exclude: Vec::new(),
+                    source: AuthenticationSource::Path("/auth/slow".into()),
+                }),
+                ..Default::default()
+            }),
+        },
+        rules: vec![
+            Rule {
+                upstream: "guarded".into(),
+                ..start_rule("/guarded", &upstream, false)
+            },
+            Rule {
+                timeouts: Some(Timeouts {
+                    response: Some(Duration::from_secs(1)),
+                    ..Default::default()
+                }),
+                ..start_rule("/patient", &upstream, false)
+            },
+            Rule {
+                timeouts: Some(Timeouts {
+                    request: Some(Duration::from_millis(200)),
+                    ..Default::default()
+                }),
+                ..start_rule("/whole", &upstream, false)
+            },
+            start_rule("/", &upstream, false),
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let res = client
+        .get(format!("{server_uri}/fast"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get(format!("{server_uri}/slow"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+    // The rule's timeout takes the place of the upstream's
+    let res = client
+        .get(format!("{server_uri}/patient/slow"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // The connection of the timed out request was closed before the upstream could respond
+    assert_eq!(upstream.requests_received().await.len(), 2);
+
+    // The request timeout lasts until the response's body is done
+    let res = client
+        .get(format!("{server_uri}/whole/trickle"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.bytes().await.is_err());
+
+    // Upgrades use the rule's timeouts too
+    let res = client
+        .get(format!("{server_uri}/patient/slow"))
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "foo")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Authentication requests time out like proxied ones
+    let res = client
+        .get(format!("{server_uri}/guarded"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn pool_warm_up() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                pool: Pool {
+                    min_idle: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Connections are opened before any requests come in
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(upstream.connections_accepted(), 2);
+
+    for _ in 0..3 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    assert_eq!(upstream.requests_received().await.len(), 3);
+    assert_eq!(upstream.connections_accepted(), 2);
+}
+
+#[tokio::test]
+async fn pool_idle_timeout() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                pool: Pool {
+                    idle_timeout: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for _ in 0..2 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    assert_eq!(upstream.connections_accepted(), 1);
+
+    // The connection expires while idle, so the next request opens a new one
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    assert_eq!(upstream.requests_received().await.len(), 3);
+    assert_eq!(upstream.connections_accepted(), 2);
+}
+
+#[tokio::test]
+async fn bounded_queue() {
+    utils::tracing();
+
+    let handler = |_: &_| async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    };
+    let full = TestUpstream::new_http1(handler).await;
+    let waiting = TestUpstream::new_http1(handler).await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            full.id().to_string() => Arc::new(Upstream {
+                addr: Some(full.addr()),
+                max_connections: 1,
+                pool: Pool {
+                    max_queued: Some(1),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            waiting.id().to_string() => Arc::new(Upstream {
+                addr: Some(waiting.addr()),
+                max_connections: 1,
+                pool: Pool {
+                    max_queue_wait: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+                // Only limits opening connections, not waiting for one
+                timeouts: Timeouts {
+                    connect: Some(Duration::from_millis(50)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            "guarded".into() => Arc::new(Upstream {
+                addr: Some(full.addr()),
+                max_connections: 1,
+                pool: Pool {
+                    max_queued: Some(0),
+                    ..Default::default()
+                },
+                authentication: Some(Authentication {
+                    exclude: Vec::new(),
+                    source: AuthenticationSource::Path("/auth".into()),
+                }),
+                ..Default::default()
+            }),
+        },
+        rules: vec![
+            start_rule("/full", &full, false),
+            start_rule("/waiting", &waiting, false),
+            Rule {
+                upstream: "guarded".into(),
+                ..start_rule("/guarded", &full, false)
+            },
+        ],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    let metrics = server.metrics();
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    let send_all = |path: &'static str, count: usize| {
+        let mut requests = JoinSet::new();
+        for _ in 0..count {
+            let request = client.get(format!("{server_uri}{path}")).send();
+            requests.spawn(async move { request.await.unwrap() });
+        }
+        requests
+    };
+
+    // One request holds the connection and one waits for it, the third doesn't fit in the queue
+    let mut requests = send_all("/full", 3);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let snapshot = metrics.upstreams()[&full.id().to_string()];
+    assert_eq!(snapshot.queued, 1);
+    assert_eq!(snapshot.in_flight, 2);
+    assert_eq!(snapshot.rejected, 1);
+
+    let mut statuses = Vec::new();
+    while let Some(res) = requests.join_next().await {
+        let res = res.unwrap();
+        if res.status() == StatusCode::SERVICE_UNAVAILABLE {
+            assert_eq!(res.headers()["retry-after"], "1");
+        }
+        statuses.push(res.status());
+    }
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::SERVICE_UNAVAILABLE
+        ]
+    );
+
+    // The second request gives up waiting before the first is done
+    let mut requests = send_all("/waiting", 2);
+    let mut statuses = Vec::new();
+    while let Some(res) = requests.join_next().await {
+        statuses.push(res.unwrap().status());
+    }
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+    assert_eq!(metrics.upstreams()[&waiting.id().to_string()].rejected, 1);
+
+    // Requests to authenticate are rejected the same way
+    let mut requests = send_all("/guarded", 2);
+    let mut statuses = Vec::new();
+    while let Some(res) = requests.join_next().await {
+        statuses.push(res.unwrap().status());
+    }
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+}
+
+#[tokio::test]
+async fn circuit_breaker() {
+    utils::tracing();
+
+    let mut upstream = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                circuit_breaker: Some(CircuitBreaker {
+                    failure_threshold: 2,
+                    open_duration: Duration::from_millis(200),
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    for _ in 0..2 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // Circuit is open, fail fast without reaching the upstream
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(upstream.requests_received().await.len(), 2);
+
+    // After `open_duration` a trial request goes through
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(upstream.requests_received().await.len(), 1);
+
+    // Authentication requests count too
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            upstream.id().to_string() => Arc::new(Upstream {
+                addr: Some(upstream.addr()),
+                circuit_breaker: Some(CircuitBreaker {
+                    failure_threshold: 1,
+                    open_duration: Duration::from_secs(60),
+                }),
+                authentication: Some(Authentication {
+                    exclude: Vec::new(),
+                    source: AuthenticationSource::Path("/auth".into()),
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &upstream, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let reqs = upstream.requests_received().await;
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].uri().path(), "/auth");
+}
+
+#[tokio::test]
+async fn backup_failover() {
+    utils::tracing();
+
+    let mut primary = TestUpstream::new_http1(|_| async move {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Empty::new().boxed())
+            .unwrap()
+    })
+    .await;
+    let mut backup = TestUpstream::new_http1(|_| async move {
+        Response::builder().body(Empty::new().boxed()).unwrap()
+    })
+    .await;
+
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            primary.id().to_string() => Arc::new(Upstream {
+                servers: vec![
+                    primary.as_server(1),
+                    UpstreamServer {
+                        backup: true,
+                        ..backup.as_server(1)
+                    },
+                ],
+                circuit_breaker: Some(CircuitBreaker {
+                    failure_threshold: 2,
+                    open_duration: Duration::from_millis(200),
+                }),
+                ..Default::default()
+            })
+        },
+        rules: vec![start_rule("/", &primary, false)],
+        ..Default::default()
+    };
+    let server = Server::new(config).unwrap();
+    let server_uri = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    let client = utils::client();
+
+    // Backups get nothing while the primary is in rotation
+    for _ in 0..2 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    assert_eq!(backup.requests_received().await.len(), 0);
+
+    // Circuit is open, so requests fail over to the backup
+    for _ in 0..2 {
+        let res = client.get(&server_uri).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    assert_eq!(primary.requests_received().await.len(), 2);
+    assert_eq!(backup.requests_received().await.len(), 2);
+
+    // The trial request after `open_duration` goes to the primary
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let res = client.get(&server_uri).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(primary.requests_received().await.len(), 1);
+}
+
+#[tokio::test]
+async fn upstream_without_servers() {
+    let config = Config {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        upstreams: hashmap! {
+            "empty".to_string() => Arc::new(Upstream::default())
+        },
+        ..Default::default()
+    };
+
+    assert!(matches!(Server::new(config), Err(crate::Error::Config(_))));
+}
+
 // TODO: make better upgrade test
 #[tokio::test]
 async fn upgrade() {
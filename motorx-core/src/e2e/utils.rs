@@ -3,31 +3,41 @@ use std::{
     future::Future,
     io::Write,
     net::SocketAddr,
+    path::Path,
+    pin::Pin,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
+    task::{ready, Context, Poll},
     time::Duration,
 };
 
 use bytes::Bytes;
 use http::{header::UPGRADE, request::Parts, Request, Response, Uri};
+use http_body::Frame;
 use http_body_util::{combinators::BoxBody, BodyExt};
-use hyper::{body::Incoming, service::service_fn};
-use hyper_util::rt::TokioIo;
+use hyper::{
+    body::Incoming,
+    service::{service_fn, Service},
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use rcgen::{CertificateParams, KeyPair};
 use reqwest::Certificate;
+use rustls::ServerConfig;
 use tempfile::NamedTempFile;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
     select,
     sync::mpsc,
+    time::Sleep,
 };
+use tokio_rustls::TlsAcceptor;
 use tracing_subscriber::EnvFilter;
 
 use crate::{
-    config::{match_type::MatchType, Upstream},
+    config::{match_type::MatchType, Upstream, UpstreamAddr, UpstreamServer},
     Rule,
 };
 
@@ -36,7 +46,7 @@ static ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 pub struct TestUpstream {
     id: usize,
     cancel_server_task: mpsc::Sender<()>,
-    socket_addr: SocketAddr,
+    addr: UpstreamAddr,
     connections_accepted: Arc<AtomicUsize>,
     connections_failed_to_accept: Arc<AtomicUsize>,
     requests_receiver: mpsc::UnboundedReceiver<Request<Bytes>>,
@@ -49,8 +59,89 @@ impl TestUpstream {
     >(
         req_handler: H,
     ) -> Self {
-        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let socket_addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self::new(req_handler, Listener::Tcp(listener), None, false).await
+    }
+
+    /// Serves http1 on `addr`
+    pub async fn new_http1_on<
+        Fut: Future<Output = Response<BoxBody<Bytes, Infallible>>> + Send + 'static,
+        H: for<'a> Fn(&'a Parts) -> Fut + Clone + Send + Sync + 'static,
+    >(
+        req_handler: H,
+        addr: SocketAddr,
+    ) -> Self {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        Self::new(req_handler, Listener::Tcp(listener), None, false).await
+    }
+
+    /// Serves http1 on a unix socket at `path`
+    pub async fn new_unix<
+        Fut: Future<Output = Response<BoxBody<Bytes, Infallible>>> + Send + 'static,
+        H: for<'a> Fn(&'a Parts) -> Fut + Clone + Send + Sync + 'static,
+    >(
+        req_handler: H,
+        path: &Path,
+    ) -> Self {
+        let listener = UnixListener::bind(path).unwrap();
+        Self::new(req_handler, Listener::Unix(listener), None, false).await
+    }
+
+    /// Serves http2 with prior knowledge (h2c)
+    pub async fn new_http2<
+        Fut: Future<Output = Response<BoxBody<Bytes, Infallible>>> + Send + 'static,
+        H: for<'a> Fn(&'a Parts) -> Fut + Clone + Send + Sync + 'static,
+    >(
+        req_handler: H,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self::new(req_handler, Listener::Tcp(listener), None, true).await
+    }
+
+    /// Serves http1, or http2 if it is negotiated with ALPN
+    pub async fn new_https<
+        Fut: Future<Output = Response<BoxBody<Bytes, Infallible>>> + Send + 'static,
+        H: for<'a> Fn(&'a Parts) -> Fut + Clone + Send + Sync + 'static,
+    >(
+        req_handler: H,
+        tls_config: Arc<ServerConfig>,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self::new(
+            req_handler,
+            Listener::Tcp(listener),
+            Some(tls_config),
+            false,
+        )
+        .await
+    }
+
+    async fn new<
+        Fut: Future<Output = Response<BoxBody<Bytes, Infallible>>> + Send + 'static,
+        H: for<'a> Fn(&'a Parts) -> Fut + Clone + Send + Sync + 'static,
+    >(
+        req_handler: H,
+        listener: Listener,
+        tls_config: Option<Arc<ServerConfig>>,
+        http2: bool,
+    ) -> Self {
+        let addr = match &listener {
+            Listener::Tcp(listener) => {
+                let scheme = if tls_config.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
+                UpstreamAddr::Uri(
+                    format!("{scheme}://{}", listener.local_addr().unwrap())
+                        .parse()
+                        .unwrap(),
+                )
+            }
+            Listener::Unix(listener) => {
+                UpstreamAddr::Unix(listener.local_addr().unwrap().as_pathname().unwrap().into())
+            }
+        };
         let (cancel_server_task, mut recv_cancel) = mpsc::channel(1);
         let (requests_sender, requests_receiver) = mpsc::unbounded_channel();
         let connections_accepted = Arc::new(AtomicUsize::new(0));
@@ -63,9 +154,9 @@ impl TestUpstream {
             async move {
                 loop {
                     select! {
-                        res = socket.accept() => {
+                        res = listener.accept() => {
                             match res {
-                                Ok((stream, _)) => {
+                                Ok(stream) => {
                                     connections_accepted.fetch_add(1, Ordering::Relaxed);
 
                                     let service = service_fn({
@@ -109,11 +200,17 @@ impl TestUpstream {
                                         }
                                     });
 
+                                    let tls_config = tls_config.clone();
                                     tokio::spawn(async move {
-                                        if let Err(_) = hyper::server::conn::http1::Builder::new()
-                                            .serve_connection(TokioIo::new(stream), service)
-                                            .with_upgrades()
-                                            .await {};
+                                        match tls_config {
+                                            Some(tls_config) => {
+                                                if let Ok(stream) = TlsAcceptor::from(tls_config).accept(stream).await {
+                                                    let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                                                    serve(stream, service, http2).await;
+                                                }
+                                            }
+                                            None => serve(stream, service, http2).await,
+                                        }
                                     });
                                 },
                                 Err(_) => {
@@ -130,7 +227,7 @@ impl TestUpstream {
         Self {
             id: ID_COUNTER.fetch_add(1, Ordering::Relaxed),
             cancel_server_task,
-            socket_addr,
+            addr,
             connections_accepted,
             connections_failed_to_accept,
             requests_receiver,
@@ -141,18 +238,19 @@ impl TestUpstream {
         self.id
     }
 
-    pub fn socket_addr(&self) -> SocketAddr {
-        self.socket_addr
+    pub fn addr(&self) -> UpstreamAddr {
+        self.addr.clone()
     }
 
     pub fn uri(&self) -> Uri {
-        format!("http://{}", self.socket_addr).parse().unwrap()
+        self.addr.uri()
     }
 
     pub fn connections_accepted(&self) -> usize {
         self.connections_accepted.load(Ordering::Relaxed)
     }
 
+    #[allow(dead_code)]
     pub fn connections_failed_to_accept(&self) -> usize {
         self.connections_failed_to_accept.load(Ordering::Relaxed)
     }
@@ -168,22 +266,161 @@ impl TestUpstream {
         requests
     }
 
+    pub fn as_server(&self, weight: u32) -> UpstreamServer {
+        UpstreamServer {
+            addr: self.addr(),
+            weight,
+            backup: false,
+        }
+    }
+
     pub fn as_upstream(&self) -> Arc<Upstream> {
         Arc::new(Upstream {
-            addr: self.uri(),
-            max_connections: 10,
-            authentication: None,
-            key: 0,
+            addr: Some(self.addr()),
+            ..Default::default()
         })
     }
 }
 
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Io for T {}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<Box<dyn Io>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            Listener::Unix(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+async fn serve<I, S>(io: I, service: S, http2: bool)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<
+            Request<Incoming>,
+            Response = Response<BoxBody<Bytes, Infallible>>,
+            Error = Infallible,
+        > + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    if http2 {
+        hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(io), service)
+            .await
+            .ok();
+    } else {
+        hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(io), service)
+            .with_upgrades()
+            .await
+            .ok();
+    }
+}
+
 impl Drop for TestUpstream {
     fn drop(&mut self) {
         self.cancel_server_task.try_send(()).ok();
     }
 }
 
+/// Upstream which reads a PROXY protocol header off each connection and sends it to the receiver,
+/// then answers each request on the connection with 200
+pub async fn proxy_protocol_upstream() -> (UpstreamAddr, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let (headers_sender, headers_receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let headers_sender = headers_sender.clone();
+
+            tokio::spawn(async move {
+                let mut header = vec![stream.read_u8().await.unwrap()];
+                if header[0] == b'P' {
+                    // v1 is a single line
+                    while !header.ends_with(b"\r\n") {
+                        header.push(stream.read_u8().await.unwrap());
+                    }
+                } else {
+                    // v2 has 16 fixed bytes, ending with the length of the addresses
+                    header.resize(16, 0);
+                    stream.read_exact(&mut header[1..]).await.unwrap();
+                    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+                    header.resize(16 + len, 0);
+                    stream.read_exact(&mut header[16..]).await.unwrap();
+                }
+                headers_sender.send(header).unwrap();
+
+                // Requests without bodies, until the connection is closed
+                loop {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read_u8().await {
+                            Ok(byte) => request.push(byte),
+                            Err(_) => return,
+                        }
+                    }
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    (addr, headers_receiver)
+}
+
+/// Body which sends `chunks` chunks, waiting `every` before each one
+pub struct SlowBody {
+    chunks: usize,
+    every: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl SlowBody {
+    pub fn new(chunks: usize, every: Duration) -> Self {
+        Self {
+            chunks,
+            every,
+            sleep: Box::pin(tokio::time::sleep(every)),
+        }
+    }
+}
+
+impl http_body::Body for SlowBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if self.chunks == 0 {
+            return Poll::Ready(None);
+        }
+
+        ready!(self.sleep.as_mut().poll(cx));
+        let next = tokio::time::Instant::now() + self.every;
+        self.sleep.as_mut().reset(next);
+        self.chunks -= 1;
+
+        Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b"chunk")))))
+    }
+}
+
 pub fn tracing() {
     static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
@@ -201,8 +438,17 @@ pub fn start_rule(starts_with: &str, upstream: &TestUpstream, remove_match: bool
         match_headers: None,
         upstream: upstream.id().to_string(),
         cache: None,
+        retry: None,
+        timeouts: None,
+        authentication: None,
+        request_headers: Default::default(),
+        response_headers: Default::default(),
+        preserve_host: None,
+        host: None,
+        rewrite: Default::default(),
         cache_key: 0,
         upstream_key: 0,
+        handler: None,
     }
 }
 
@@ -259,3 +505,19 @@ pub fn gen_self_signed() -> CertKeyFiles {
         key_file,
     }
 }
+
+/// Server config for the cert and key, accepting the ALPN `protocols`
+pub fn tls_server_config(files: &CertKeyFiles, protocols: &[&[u8]]) -> Arc<ServerConfig> {
+    crate::tls::install_crypto_provider();
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            crate::tls::load_certs(files.cert_file.path()).unwrap(),
+            crate::tls::load_private_key(files.key_file.path()).unwrap(),
+        )
+        .unwrap();
+    config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+
+    Arc::new(config)
+}
@@ -4,6 +4,20 @@ use thiserror::Error;
 pub enum Error {
     #[error("Io error: {0:?}")]
     Io(#[from] std::io::Error),
+    #[error("Invalid config: {0}")]
+    Config(String),
+    #[error("Too many requests are waiting for an upstream connection")]
+    QueueFull,
+    #[error("Timed out waiting for an upstream connection")]
+    QueueTimeout,
+    #[error("Timed out {0}")]
+    Timeout(&'static str),
+    #[error("Handler error: {0}")]
+    Handler(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Request body error: {0}")]
+    Body(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Layer error: {0}")]
+    Layer(Box<dyn std::error::Error + Send + Sync>),
     #[error("Hyper error: {0:?}")]
     Hyper(#[from] hyper::Error),
     #[cfg(feature = "tls")]
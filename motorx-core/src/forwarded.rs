@@ -0,0 +1,179 @@
+use std::net::{IpAddr, SocketAddr};
+
+use http::{
+    header::{FORWARDED, VIA},
+    HeaderMap, HeaderName, HeaderValue, Request, Version,
+};
+use ipnet::IpNet;
+
+use crate::config::rule::request_host;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+static X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// Works out the client's ip and sets the headers telling upstreams about it.
+///
+/// Forwarding headers are only read from peers in `trusted_proxies`, they are removed from other requests.
+/// The client is the last address in the chain which isn't a trusted proxy
+pub(crate) fn forward<B>(
+    req: &mut Request<B>,
+    peer: IpAddr,
+    trusted_proxies: &[IpNet],
+    tls: bool,
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let request_host = request_host(req).map(str::to_string);
+    let headers = req.headers_mut();
+
+    let (chain, client, proto, host) = if is_trusted(&peer) {
+        let chain = joined(headers, &X_FORWARDED_FOR);
+        let hops = match chain.as_deref() {
+            Some(chain) => chain.split(',').map(parse_node).collect(),
+            None => forwarded_for(headers),
+        };
+
+        // Walk back from the proxy closest to us until reaching an address we don't trust
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = *ip;
+                    if !is_trusted(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        let proto = first(headers, &X_FORWARDED_PROTO);
+        let host = first(headers, &X_FORWARDED_HOST);
+        (chain, client, proto, host)
+    } else {
+        for name in [
+            &FORWARDED,
+            &X_FORWARDED_FOR,
+            &X_FORWARDED_PROTO,
+            &X_FORWARDED_HOST,
+            &X_REAL_IP,
+        ] {
+            headers.remove(name);
+        }
+        (None, peer, None, None)
+    };
+
+    let proto = proto.unwrap_or_else(|| if tls { "https" } else { "http" }.to_string());
+    let host = host.or(request_host);
+
+    let for_node = match peer {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!(r#""[{v6}]""#),
+    };
+    let mut forwarded = format!("for={for_node};proto={proto}");
+    if let Some(host) = &host {
+        forwarded.push_str(&format!(r#";host="{host}""#));
+    }
+    append(headers, &FORWARDED, &forwarded);
+
+    let forwarded_for = match chain {
+        Some(chain) => format!("{chain}, {peer}"),
+        None => peer.to_string(),
+    };
+    set(headers, &X_FORWARDED_FOR, &forwarded_for);
+    set(headers, &X_FORWARDED_PROTO, &proto);
+    if let Some(host) = &host {
+        set(headers, &X_FORWARDED_HOST, host);
+    }
+    set(headers, &X_REAL_IP, &client.to_string());
+
+    client
+}
+
+/// Adds this proxy to the `Via` headers of a request or response received over `version`
+pub(crate) fn add_via(headers: &mut HeaderMap, version: Version, proxy_name: &str) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+
+    append(headers, &VIA, &format!("{protocol} {proxy_name}"));
+}
+
+/// Whether the request already passed through this proxy
+pub(crate) fn is_loop(headers: &HeaderMap, proxy_name: &str) -> bool {
+    headers
+        .get_all(VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        // ex. `1.1 proxy-name (comment)`
+        .filter_map(|entry| entry.split_whitespace().nth(1))
+        .any(|received_by| received_by == proxy_name)
+}
+
+/// Every value of the header, joined into one list
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// First item of the header's list
+fn first(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let item = value.split(',').next()?.trim();
+    (!item.is_empty()).then(|| item.to_string())
+}
+
+/// `for` addresses of the elements in the `Forwarded` headers
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let Some(forwarded) = joined(headers, &FORWARDED) else {
+        return Vec::new();
+    };
+
+    forwarded
+        .split(',')
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .map(|node| node.and_then(parse_node))
+        .collect()
+}
+
+/// Ip of a node in a chain, which may be quoted, or have a port (ex. `"[::1]:8080"`)
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+fn set(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name.clone(), value);
+    }
+}
+
+fn append(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.append(name.clone(), value);
+    }
+}
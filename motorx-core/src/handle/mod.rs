@@ -1,54 +1,120 @@
+mod rewrite;
 mod upgrade;
 pub mod util;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Weak};
 use std::time::Instant;
 
-use bytes::Bytes;
-use http::header::{CONNECTION, UPGRADE};
-use http_body_util::combinators::BoxBody;
-use hyper::{body::Incoming, Method, StatusCode};
+use http::header::UPGRADE;
+use http::HeaderValue;
+use hyper::{Method, StatusCode};
 use hyper::{Request, Response};
-use util::proxy_request;
 
-use crate::cache::{Cache, CacheEntry, CloneableRes};
+use crate::cache::{CacheEntry, CloneableRes};
+use crate::config::authentication::AuthenticationSource;
+use crate::config::headers::HeaderVars;
 use crate::config::rule::Rule;
-use crate::config::Config;
-use crate::{cfg_logging, UpstreamAndConnPool, Upstreams};
+use crate::handler::Handler;
+use crate::proxy_protocol::ClientAddrs;
+#[cfg(feature = "logging")]
+use crate::request_id::RequestId;
+use crate::service::{self, Shared};
+use crate::{cfg_logging, forwarded, Body, UpstreamAndBalancer, Upstreams};
 
 #[cfg_attr(
     feature = "logging",
-    tracing::instrument(level = "trace", skip(req, config, cache))
+    tracing::instrument(
+        level = "trace",
+        skip(req, shared),
+        fields(request_id = req.extensions().get::<RequestId>().and_then(|id| id.0.to_str().ok()))
+    )
 )]
 pub(crate) async fn handle_req(
-    req: Request<hyper::body::Incoming>,
+    mut req: Request<Body>,
     peer_addr: SocketAddr,
-    config: Arc<Config>,
-    cache: Arc<Cache>,
-    upstreams: Arc<Upstreams>,
-) -> Result<Response<BoxBody<Bytes, crate::Error>>, crate::Error> {
+    shared: Arc<Shared>,
+) -> Result<Response<Body>, crate::Error> {
+    let (config, upstreams) = (&shared.config, &shared.upstreams);
+
+    if forwarded::is_loop(req.headers(), &config.proxy_name) {
+        cfg_logging! {warn!("Rejecting request which looped back to this proxy: {}", req.uri());}
+        return Ok(Response::builder()
+            .status(StatusCode::LOOP_DETECTED)
+            .body(util::empty())
+            .unwrap());
+    }
+
     for rule in &config.rules {
         if rule.matches(&req) {
+            let client_ip = forwarded::forward(
+                &mut req,
+                peer_addr.ip(),
+                &config.trusted_proxies,
+                config.tls.is_some(),
+            );
+            // PROXY protocol headers name the client, not the proxy in front of it
+            if let Some(client) = req.extensions_mut().get_mut::<ClientAddrs>() {
+                *client = client.for_client(client_ip);
+            }
+
+            if let Some(handler) = &rule.handler {
+                return handle_local(req, client_ip, rule, handler, &shared).await;
+            }
+
             let upstream = upstreams.get(rule.upstream_key).expect("`upstream` in a rule should match a key in the `upstreams` property at the root of the config.");
 
+            let host = rule.upstream_host(&upstream.0, &req);
+            let version = req.version();
+            forwarded::add_via(req.headers_mut(), version, &config.proxy_name);
+
+            // Variables in header values and dynamic addresses come from the request as the client sent it
+            let header_ops = [
+                &upstream.0.request_headers,
+                &rule.request_headers,
+                &upstream.0.response_headers,
+                &rule.response_headers,
+            ];
+            let header_vars = (header_ops.iter().any(|ops| !ops.is_empty())
+                || is_dynamic(upstreams, upstream))
+            .then(|| HeaderVars::new(&req, client_ip, rule));
+            if let Some(vars) = header_vars.as_ref() {
+                req.extensions_mut().insert(vars.clone());
+            }
+
             // handle authentication if necessary
-            let auth_res = util::authenticate(&upstreams, upstream, peer_addr, &req).await?;
+            let auth_res =
+                util::authenticate(upstreams, upstream, client_ip, host.as_ref(), rule, &req)
+                    .await?;
 
             if let Some(res) = auth_res {
                 return Ok(res);
             };
 
-            return handle_match(
+            if let Some(vars) = header_vars.as_ref() {
+                upstream.0.request_headers.apply(req.headers_mut(), vars);
+                rule.request_headers.apply(req.headers_mut(), vars);
+            }
+
+            let mut res = handle_match(
                 req,
-                peer_addr,
+                client_ip,
+                host.as_ref(),
                 rule,
-                upstream,
-                cache,
-                &upstreams,
-                config.max_connections,
+                Target::Upstream(upstream),
+                &shared,
             )
-            .await;
+            .await?;
+
+            let version = res.version();
+            forwarded::add_via(res.headers_mut(), version, &config.proxy_name);
+
+            if let Some(vars) = header_vars.as_ref() {
+                upstream.0.response_headers.apply(res.headers_mut(), vars);
+                rule.response_headers.apply(res.headers_mut(), vars);
+            }
+
+            return Ok(res);
         }
     }
 
@@ -58,19 +124,61 @@ pub(crate) async fn handle_req(
         .unwrap())
 }
 
+/// Serves a request matching `rule` with its handler, it only gets the rule's header changes
+async fn handle_local(
+    mut req: Request<Body>,
+    client_ip: IpAddr,
+    rule: &Rule,
+    handler: &Handler,
+    shared: &Shared,
+) -> Result<Response<Body>, crate::Error> {
+    let header_vars = (!rule.request_headers.is_empty() || !rule.response_headers.is_empty())
+        .then(|| HeaderVars::new(&req, client_ip, rule));
+    if let Some(vars) = header_vars.as_ref() {
+        rule.request_headers.apply(req.headers_mut(), vars);
+    }
+
+    let mut res =
+        handle_match(req, client_ip, None, rule, Target::Handler(handler), shared).await?;
+
+    if let Some(vars) = header_vars.as_ref() {
+        rule.response_headers.apply(res.headers_mut(), vars);
+    }
+
+    Ok(res)
+}
+
+/// What a matched request is sent to
+#[derive(Debug, Clone, Copy)]
+enum Target<'a> {
+    Upstream(&'a UpstreamAndBalancer),
+    Handler(&'a Handler),
+}
+
+/// Whether a server for the request is picked from its variables, by `upstream` or the upstream it authenticates with
+fn is_dynamic(upstreams: &Upstreams, upstream: &UpstreamAndBalancer) -> bool {
+    let auth_upstream = match upstream.0.authentication.as_ref().map(|auth| &auth.source) {
+        Some(AuthenticationSource::Upstream { key, .. }) => upstreams.get(*key),
+        _ => None,
+    };
+
+    upstream.0.dynamic.is_some() || auth_upstream.is_some_and(|auth| auth.0.dynamic.is_some())
+}
+
 #[cfg_attr(
     feature = "logging",
-    tracing::instrument(level = "trace", skip(req, cache, peer_addr))
+    tracing::instrument(level = "trace", skip(req, shared, client_ip))
 )]
 async fn handle_match(
-    mut req: Request<Incoming>,
-    peer_addr: SocketAddr,
+    mut req: Request<Body>,
+    client_ip: IpAddr,
+    host: Option<&HeaderValue>,
     rule: &Rule,
-    upstream: &UpstreamAndConnPool,
-    cache: Arc<Cache>,
-    upstreams: &Upstreams,
-    max_connections: usize,
-) -> Result<Response<BoxBody<Bytes, crate::Error>>, crate::Error> {
+    target: Target<'_>,
+    shared: &Shared,
+) -> Result<Response<Body>, crate::Error> {
+    let (cache, max_connections) = (&shared.cache, shared.config.max_connections);
+
     if Method::CONNECT == req.method() {
         // Don't feel comfortable supporting Connect method right now
         return Ok(Response::builder()
@@ -79,19 +187,24 @@ async fn handle_match(
             .unwrap());
     }
 
-    *req.uri_mut() = rule.remove_match(req.uri().path()).parse().unwrap();
+    let path = rule.remove_match(req.uri().path());
+    *req.uri_mut() = match req.uri().query() {
+        Some(query) => format!("{path}?{query}").parse().unwrap(),
+        None => path.parse().unwrap(),
+    };
 
     // We got an upgrade request if:
     //   - the request has "connection" and "upgrade" headers
-    //   - "connection" == "upgrade"
+    //   - "connection" lists "upgrade"
     //   - "upgrade" is not empty
-    let connection_header = req.headers().get(CONNECTION);
     let upgrade_header = req.headers().get(UPGRADE);
-    let upgrading = connection_header.is_some_and(|v| v.as_bytes() == b"upgrade")
+    let upgrading = util::connection_options(req.headers())
+        .any(|option| option.eq_ignore_ascii_case("upgrade"))
         && upgrade_header.is_some_and(|v| !v.is_empty());
 
-    if upgrading {
-        return upgrade::handle_upgrade(req, upstream, peer_addr).await;
+    if let (true, Target::Upstream(upstream)) = (upgrading, target) {
+        return upgrade::handle_upgrade(req, upstream, client_ip, host, rule.timeouts.as_ref())
+            .await;
     }
 
     // use cache if enabled and not upgrading
@@ -123,7 +236,7 @@ async fn handle_match(
                     // dont hold lock while waiting for inflight
                     if let Ok(Some(res)) = inflight.subscribe().recv().await {
                         // Clone the inner response and use it
-                        return Ok((*res).clone().0.map(|b| util::full(b)));
+                        return Ok((*res).clone().0.map(util::full));
                     } else {
                         // inflight request failed, proceed as if caching was disabled
                         None
@@ -156,12 +269,23 @@ async fn handle_match(
     };
 
     let req_uri = req.uri().clone();
-    let resp = util::proxy_request(req, upstream, peer_addr, false).await;
-    cfg_logging! {
-        trace!("Got res from upstream {}", peer_addr);
-    }
+    let mut resp = match target {
+        Target::Upstream(upstream) => {
+            let public_url = rewrite::PublicUrl::new(&req, rule);
+            let mut resp =
+                service::call_upstream(shared, req, upstream, client_ip, host, rule).await;
+            rewrite::rewrite_response(&mut resp, &rule.rewrite, &public_url);
+            cfg_logging! {
+                trace!("Got res from upstream {}", client_ip);
+            }
+            resp
+        }
+        Target::Handler(handler) => handler.call(req).await,
+    };
+    // Only pins this client, so it is left out of the cache
+    let sticky_cookie = util::SetStickyCookie::take(&mut resp);
 
-    if let Some(refresh_cache) = refresh_cache {
+    let mut resp = if let Some(refresh_cache) = refresh_cache {
         // read response & clone to send one and save one for cache
         let status = resp.status();
 
@@ -208,12 +332,18 @@ async fn handle_match(
             resp
         };
 
-        Ok(resp)
+        resp
     } else {
         // Just send response
         cfg_logging! {
-            trace!("Returning res form upstream {}", peer_addr);
+            trace!("Returning res form upstream {}", client_ip);
         }
-        Ok(resp)
+        resp
+    };
+
+    if let Some(cookie) = sticky_cookie {
+        cookie.add_to(&mut resp);
     }
+
+    Ok(resp)
 }
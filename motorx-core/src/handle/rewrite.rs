@@ -0,0 +1,179 @@
+use http::{
+    header::{CONTENT_LOCATION, LOCATION, SET_COOKIE},
+    HeaderMap, HeaderName, HeaderValue, Request, Response, Uri,
+};
+
+use crate::config::{match_type::MatchType, rewrite::Rewrite, Rule};
+
+/// Server a response came from, stored in the response's extensions
+#[derive(Debug, Clone)]
+pub(crate) struct ServedBy {
+    /// `Host` the request was sent to the server with
+    pub(crate) host: HeaderValue,
+    /// Path of the server's address, which the request's path was joined onto
+    pub(crate) base_path: String,
+}
+
+/// Url the client sent a request to
+#[derive(Debug)]
+pub(crate) struct PublicUrl {
+    /// Scheme and host the client used, ex. `https://example.com`
+    origin: Option<String>,
+    /// Part of the path removed by the rule
+    prefix: String,
+}
+
+impl PublicUrl {
+    /// Read from the forwarding headers, so must be called after they are set
+    pub(crate) fn new<B>(req: &Request<B>, rule: &Rule) -> Self {
+        let first = |name: &str| {
+            let value = req.headers().get(name)?.to_str().ok()?;
+            value.split(',').next().map(str::trim)
+        };
+        let origin = first("x-forwarded-proto")
+            .zip(first("x-forwarded-host"))
+            .map(|(proto, host)| format!("{proto}://{host}"));
+
+        let prefix = match &rule.path {
+            MatchType::Start(start) if rule.remove_match => start.trim_end_matches('/').to_string(),
+            _ => String::new(),
+        };
+
+        Self { origin, prefix }
+    }
+
+    /// Path on the client's side for a path on the server's side
+    fn path(&self, served: &ServedBy, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(served.base_path.as_str())?;
+        if !(rest.is_empty() || rest.starts_with('/')) {
+            // `/base` doesn't contain `/based`
+            return None;
+        }
+
+        let rest = if rest.is_empty() { "/" } else { rest };
+        Some(format!("{}{rest}", self.prefix))
+    }
+
+    /// `Location` on the client's side, `None` for urls which don't point at the server
+    fn location(&self, served: &ServedBy, location: &str) -> Option<String> {
+        let (path_and_rest, absolute) = if location.starts_with('/') && !location.starts_with("//")
+        {
+            (location, false)
+        } else {
+            let uri = location.parse::<Uri>().ok()?;
+            let authority = uri.authority()?.as_str();
+            if !served
+                .host
+                .as_bytes()
+                .eq_ignore_ascii_case(authority.as_bytes())
+            {
+                return None;
+            }
+
+            let after_authority = location.find("://")? + 3 + authority.len();
+            let rest = &location[after_authority..];
+            (if rest.is_empty() { "/" } else { rest }, true)
+        };
+
+        let path_end = path_and_rest
+            .find(['?', '#'])
+            .unwrap_or(path_and_rest.len());
+        let path = self.path(served, &path_and_rest[..path_end])?;
+        let query_and_fragment = &path_and_rest[path_end..];
+
+        Some(match (&self.origin, absolute) {
+            (Some(origin), true) => format!("{origin}{path}{query_and_fragment}"),
+            _ => format!("{path}{query_and_fragment}"),
+        })
+    }
+
+    /// `Set-Cookie` with its `Path` and `Domain` on the client's side
+    fn cookie(&self, served: &ServedBy, rewrite: &Rewrite, cookie: &str) -> String {
+        let server_domain = served
+            .host
+            .to_str()
+            .ok()
+            .map(|host| host.rsplit_once(':').map_or(host, |(domain, _)| domain));
+
+        let mut parts = cookie.split(';').map(str::trim);
+        let mut rewritten = parts.next().unwrap_or_default().to_string();
+        for attribute in parts {
+            let attribute = match attribute.split_once('=') {
+                Some((key, path)) if rewrite.cookie_path && key.eq_ignore_ascii_case("path") => {
+                    match self.path(served, path) {
+                        Some(public) if public.len() > 1 => {
+                            format!("{key}={}", public.trim_end_matches('/'))
+                        }
+                        Some(public) => format!("{key}={public}"),
+                        None => attribute.to_string(),
+                    }
+                }
+                Some((key, domain)) if key.eq_ignore_ascii_case("domain") => {
+                    let bare = domain.trim_start_matches('.');
+                    match rewrite.cookie_domains.get(bare) {
+                        Some(public) => format!("{key}={public}"),
+                        None if rewrite.remove_cookie_domain
+                            && server_domain
+                                .is_some_and(|server| server.eq_ignore_ascii_case(bare)) =>
+                        {
+                            continue;
+                        }
+                        None => attribute.to_string(),
+                    }
+                }
+                _ => attribute.to_string(),
+            };
+
+            rewritten.push_str("; ");
+            rewritten.push_str(&attribute);
+        }
+
+        rewritten
+    }
+}
+
+/// Maps urls of the server which sent `res` back to the client's url
+pub(crate) fn rewrite_response<B>(res: &mut Response<B>, rewrite: &Rewrite, public: &PublicUrl) {
+    let Some(served) = res.extensions().get::<ServedBy>().cloned() else {
+        // motorx made this response itself
+        return;
+    };
+    let headers = res.headers_mut();
+
+    if rewrite.location {
+        for name in [LOCATION, CONTENT_LOCATION] {
+            map_values(headers, name, |location| public.location(&served, location));
+        }
+    }
+
+    if rewrite.rewrites_cookies() {
+        map_values(headers, SET_COOKIE, |cookie| {
+            Some(public.cookie(&served, rewrite, cookie))
+        });
+    }
+}
+
+/// Replaces each value of the header `f` returns a new value for
+fn map_values(headers: &mut HeaderMap, name: HeaderName, f: impl Fn(&str) -> Option<String>) {
+    if !headers.contains_key(&name) {
+        return;
+    }
+
+    let values = headers
+        .get_all(&name)
+        .iter()
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(&f)
+                .and_then(|mapped| HeaderValue::from_str(&mapped).ok())
+                .unwrap_or_else(|| value.clone())
+        })
+        .collect::<Vec<_>>();
+
+    headers.remove(&name);
+    for value in values {
+        headers.append(&name, value);
+    }
+}
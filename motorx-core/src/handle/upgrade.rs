@@ -1,19 +1,20 @@
-use std::net::SocketAddr;
+use std::net::IpAddr;
 
 use bytes::Bytes;
-use http::{Request, Response};
+use http::{HeaderValue, Request, Response};
 use http_body_util::{combinators::BoxBody, Empty};
-use hyper::body::Incoming;
 use hyper_util::rt::TokioIo;
 
-use crate::{cfg_logging, UpstreamAndConnPool};
+use crate::{cfg_logging, config::Timeouts, UpstreamAndBalancer};
 
 use super::util;
 
 pub(crate) async fn handle_upgrade(
-    req: Request<Incoming>,
-    upstream: &UpstreamAndConnPool,
-    peer_addr: SocketAddr,
+    req: Request<crate::Body>,
+    upstream: &UpstreamAndBalancer,
+    client_ip: IpAddr,
+    host: Option<&HeaderValue>,
+    timeouts: Option<&Timeouts>,
 ) -> Result<Response<BoxBody<Bytes, crate::Error>>, crate::Error> {
     // First, proxy upgrade request to upstream to see if it is successful
 
@@ -26,7 +27,11 @@ pub(crate) async fn handle_upgrade(
             Request::from_parts(og_head, Empty::<Bytes>::new()),
         )
     };
-    let mut res = util::proxy_request(client_req, upstream, peer_addr, true).await;
+    let mut res =
+        util::proxy_request(client_req, upstream, client_ip, host, true, None, timeouts).await;
+    if let Some(cookie) = util::SetStickyCookie::take(&mut res) {
+        cookie.add_to(&mut res);
+    }
 
     match hyper::upgrade::on(&mut res).await {
         Ok(upgraded_upstream) => {
@@ -1,93 +1,129 @@
-use std::net::SocketAddr;
+use std::{net::IpAddr, sync::Arc};
 
 use bytes::Bytes;
-use http::{header::HOST, HeaderValue, Request, Response, StatusCode};
+use http::{
+    header::{
+        CONNECTION, COOKIE, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, RETRY_AFTER, SET_COOKIE,
+        TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri,
+};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
-use hyper::{body::Incoming, client, upgrade::Upgraded};
-use hyper_util::rt::TokioIo;
+use hyper::body::Body;
 
+use super::rewrite::ServedBy;
 use crate::{
+    balancer::Backend,
     cfg_logging,
-    config::{authentication::AuthenticationSource, Upstream},
-    tcp_connect, UpstreamAndConnPool, Upstreams,
+    config::{authentication::AuthenticationSource, Retry, RetryOn, Rule, Timeouts},
+    proxy_protocol::ClientAddrs,
+    retry::{
+        backoff, is_idempotent, retries_after_sending, RetryBody, DEFAULT_RETRY, MAX_RETRY_BODY,
+    },
+    timeout::{within, Deadline, IdleTimeout},
+    UpstreamAndBalancer, Upstreams,
 };
 
-pub(crate) fn add_proxy_headers<B>(
-    req: &mut Request<B>,
-    upstream: &Upstream,
-    peer_addr: SocketAddr,
-) {
-    let proto = req.uri().scheme_str().unwrap_or_default();
-    let proto = if proto.is_empty() {
-        proto.to_string()
-    } else {
-        format!("proto={}", proto)
-    };
+/// Replaces the request's `Host`, with the server it is sent to unless the rule or upstream chose another
+pub(crate) fn set_host<B>(req: &mut Request<B>, host: &HeaderValue) {
+    req.headers_mut().insert(HOST, host.clone());
+}
 
-    let host = req
-        .headers()
-        .get("host")
-        .map(|h| h.to_str().unwrap_or_default())
-        .unwrap_or_default();
-    let host = if host.is_empty() {
-        host.to_string()
-    } else {
-        format!("host={};", host)
+/// `uri`'s path and query, with the path joined onto a server's `base_path`
+pub(crate) fn upstream_uri(base_path: &str, uri: &Uri) -> Uri {
+    if base_path.is_empty() {
+        return uri.clone();
+    }
+
+    let path = uri.path();
+    let slash = if path.starts_with('/') { "" } else { "/" };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{base_path}{slash}{path}?{query}"),
+        None => format!("{base_path}{slash}{path}"),
     };
 
-    let headers = req.headers_mut();
-    headers.append(
-        "forwarded",
-        HeaderValue::from_str(&format!(
-            "for={};{}{}",
-            match peer_addr {
-                SocketAddr::V4(v4) => v4.to_string(),
-                SocketAddr::V6(v6) => {
-                    format!(r#""{}""#, v6)
-                }
-            },
-            host,
-            proto
-        ))
-        .unwrap(),
-    );
-    headers.append(
-        "x-forwarded-for",
-        HeaderValue::from_str(&format!("{}", peer_addr)).unwrap(),
-    );
-    // change host to be correct for the upstream
-    headers.insert(
-        HOST,
-        upstream
-            .addr
-            .authority()
-            .unwrap()
-            .as_str()
-            .try_into()
-            .unwrap(),
-    );
+    // Both parts were already valid in a uri, so joining them is too
+    path_and_query.parse().unwrap_or_else(|_| uri.clone())
 }
 
-const HOP_HEADERS_NO_UPGRADE: [&str; 6] = [
-    "keep-alive",
-    "proxy-authenticate",
-    "proxy-authorization",
-    "tt",
-    "trailer",
-    "transfer-encoding",
+/// Headers which only apply to a single connection (RFC 9110 7.6.1)
+const HOP_HEADERS: [HeaderName; 9] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
 ];
 
-pub(crate) fn remove_hop_headers<B>(req: &mut Request<B>, upgrading: bool) {
-    let headers = req.headers_mut();
-    for hop_header in HOP_HEADERS_NO_UPGRADE {
-        headers.remove(hop_header);
+/// Options listed in the `Connection` headers
+pub(crate) fn connection_options(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+}
+
+/// Removes hop-by-hop headers from a request or response, including those named in `Connection`.
+/// `TE: trailers` is kept since it is meant for the server (ex. gRPC),
+/// and upgrades keep `Upgrade` with `Connection: upgrade`
+pub(crate) fn remove_hop_headers(headers: &mut HeaderMap, upgrading: bool) {
+    let named = connection_options(headers)
+        .filter_map(|option| HeaderName::from_bytes(option.as_bytes()).ok())
+        .filter(|name| !(upgrading && name == UPGRADE))
+        .collect::<Vec<_>>();
+    for name in named {
+        headers.remove(name);
     }
 
-    if !upgrading {
-        for hop_header in ["connection", "upgrade"] {
-            headers.remove(hop_header);
+    let keep_te = headers
+        .get_all(TE)
+        .iter()
+        .all(|te| te.as_bytes().eq_ignore_ascii_case(b"trailers"));
+    for name in HOP_HEADERS {
+        let kept = (name == TE && keep_te) || (upgrading && name == UPGRADE);
+        if !kept {
+            headers.remove(name);
         }
     }
+
+    if upgrading {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+/// Value of the first cookie named `name` in the request's `Cookie` headers
+pub(crate) fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (k, v) = pair.trim().split_once('=')?;
+            (k == name).then_some(v)
+        })
+}
+
+/// `Set-Cookie` pinning the client to the server which answered, kept in the response's extensions
+/// until it's added to the response sent to the client, so it isn't cached or rewritten
+#[derive(Clone)]
+pub(crate) struct SetStickyCookie(HeaderValue);
+
+impl SetStickyCookie {
+    pub(crate) fn take<B>(res: &mut Response<B>) -> Option<Self> {
+        res.extensions_mut().remove()
+    }
+
+    pub(crate) fn add_to<B>(self, res: &mut Response<B>) {
+        res.headers_mut().append(SET_COOKIE, self.0);
+    }
 }
 
 pub(crate) fn empty() -> BoxBody<Bytes, crate::Error> {
@@ -121,72 +157,201 @@ pub(crate) async fn clone_response<T: BodyExt>(
     res: Response<T>,
 ) -> Result<(Response<BoxBody<Bytes, crate::Error>>, Response<Bytes>), T::Error> {
     let (parts, og_body) = res.into_parts();
-    let body = read_body::<_, crate::Error>(og_body).await?;
+    let body = read_body(og_body).await?;
 
-    return Ok((
+    Ok((
         Response::from_parts(parts.clone(), full(body.clone())),
         Response::from_parts(parts, body),
-    ));
+    ))
 }
 
 #[inline]
-pub(crate) async fn read_body<B: BodyExt, E>(body: B) -> Result<Bytes, B::Error> {
+pub(crate) async fn read_body<B: BodyExt>(body: B) -> Result<Bytes, B::Error> {
     Ok(body.collect().await?.to_bytes())
 }
 
 pub(crate) async fn proxy_request(
-    mut req: Request<Incoming>,
-    upstream: &UpstreamAndConnPool,
-    peer_addr: SocketAddr,
+    req: Request<crate::Body>,
+    upstream: &UpstreamAndBalancer,
+    client_ip: IpAddr,
+    host: Option<&HeaderValue>,
     upgrading: bool,
+    retry: Option<&Retry>,
+    timeouts: Option<&Timeouts>,
 ) -> Response<BoxBody<Bytes, crate::Error>> {
-    const RETRY_COUNT: usize = 1;
-    let mut tries = 0;
-
-    let mut conn = loop {
-        if tries > RETRY_COUNT {
-            return bad_gateway();
+    let timeouts = upstream.0.timeouts.overridden_by(timeouts);
+    let started = tokio::time::Instant::now();
+
+    match within(
+        timeouts.request,
+        send_with_retries(req, upstream, client_ip, host, upgrading, retry, &timeouts),
+    )
+    .await
+    {
+        // The rest of the exchange is the response's body
+        Ok(resp) => match timeouts.request {
+            Some(request) => resp.map(|body| Deadline::new(body, started + request).boxed()),
+            None => resp,
+        },
+        Err(_) => {
+            cfg_logging! {error!("Request to upstream timed out");}
+            gateway_timeout()
         }
+    }
+}
 
-        let mut conn = match upstream.1.get_sender().await {
-            Ok(senders) => senders,
-            Err(err) => {
-                cfg_logging! {error!("Failed to connect to {}: {err}", upstream.0.addr);}
-                tries += 1;
-                continue;
+async fn send_with_retries(
+    mut req: Request<crate::Body>,
+    upstream: &UpstreamAndBalancer,
+    client_ip: IpAddr,
+    host: Option<&HeaderValue>,
+    upgrading: bool,
+    retry: Option<&Retry>,
+    timeouts: &Timeouts,
+) -> Response<BoxBody<Bytes, crate::Error>> {
+    let retry = retry.unwrap_or(&DEFAULT_RETRY);
+    let balancer = &upstream.1;
+
+    balancer.retry_budget.record_request();
+
+    remove_hop_headers(req.headers_mut(), upgrading);
+    let idempotent = retry.non_idempotent || is_idempotent(req.method());
+    let (parts, body) = req.into_parts();
+    let client = parts.extensions.get::<ClientAddrs>().copied();
+
+    // Small bodies are read ahead of time, so they can be sent again after reaching a server.
+    // Failing to connect gives the body back unsent, so it doesn't need to be read for that
+    let mut body = if retry.attempts > 1
+        && retries_after_sending(retry)
+        && idempotent
+        && !upgrading
+        && body
+            .size_hint()
+            .upper()
+            .is_some_and(|len| len <= MAX_RETRY_BODY)
+    {
+        match read_body(body).await {
+            Ok(bytes) => RetryBody::Buffered(bytes),
+            Err(_err) => {
+                cfg_logging! {debug!("Failed to read request body: {_err}");}
+                return bad_request();
             }
-        };
-
-        if let Err(err) = conn.ready().await {
-            cfg_logging! {error!("Connection to {} was unexpectedly closed: {err}", upstream.0.addr);}
-            tries += 1;
-            continue;
         }
-
-        break conn;
+    } else {
+        RetryBody::Streaming(Some(body))
     };
+    let mut tried: Vec<Arc<Backend>> = Vec::new();
 
-    // wait for conn to be ready, if it closes return a error
+    loop {
+        let mut req = Request::from_parts(
+            parts.clone(),
+            body.take().expect("body is checked before retrying"),
+        );
 
-    add_proxy_headers(&mut req, &upstream.0, peer_addr);
-    remove_hop_headers(&mut req, upgrading);
+        let Some(backend) = balancer.pick_excluding(&req, client_ip, &tried) else {
+            cfg_logging! {error!("No available servers for upstream");}
+            return service_unavailable();
+        };
 
-    cfg_logging! {
-        debug!("Proxying request: {:?}", req);
-    }
+        let (failure, resp) = 'attempt: {
+            let conn = async {
+                let mut conn = backend
+                    .pool
+                    .get_sender(client.as_ref(), timeouts.connect)
+                    .await?;
+                // wait for conn to be ready, if it closes return a error
+                conn.ready().await?;
+                Ok::<_, crate::Error>(conn)
+            };
+            let mut conn = match conn.await {
+                Ok(conn) => conn,
+                Err(_err @ (crate::Error::QueueFull | crate::Error::QueueTimeout)) => {
+                    cfg_logging! {warn!("Rejecting request to {}: {_err}", backend);}
+                    return queue_rejected();
+                }
+                Err(crate::Error::Timeout(_)) => {
+                    cfg_logging! {error!("Timed out connecting to {}", backend);}
+                    balancer.record_outcome(&backend, false);
+                    body.put_back(req.into_body());
+                    break 'attempt (RetryOn::Connect, gateway_timeout());
+                }
+                Err(_err) => {
+                    cfg_logging! {error!("Failed to connect to {}: {_err}", backend);}
+                    balancer.record_outcome(&backend, false);
+                    body.put_back(req.into_body());
+                    break 'attempt (RetryOn::Connect, bad_gateway());
+                }
+            };
 
-    let resp = match conn.send_request(req).await {
-        Ok(resp) => resp,
-        Err(err) => {
-            cfg_logging! {error!("Failed to proxy request to {}: {err}", upstream.0.addr);};
-            return bad_gateway();
-        }
-    };
+            let sticky_cookie = balancer.sticky_cookie(req.headers(), &backend);
+            let served_host = host.unwrap_or(&backend.host).clone();
+            set_host(&mut req, &served_host);
+            *req.uri_mut() = upstream_uri(backend.addr.base_path(), req.uri());
+
+            cfg_logging! {
+                debug!("Proxying request: {:?}", req);
+            }
 
-    // Dropping a pooled connection returns it to the pool
-    drop(conn);
+            let resp = match within(timeouts.response, conn.send_request(req)).await {
+                Ok(Ok(resp)) => resp,
+                Ok(Err(_err)) => {
+                    cfg_logging! {error!("Failed to proxy request to {}: {_err}", backend);};
+                    balancer.record_outcome(&backend, false);
+                    break 'attempt (RetryOn::Reset, bad_gateway());
+                }
+                Err(_) => {
+                    cfg_logging! {error!("Timed out waiting for a response from {}", backend);}
+                    balancer.record_outcome(&backend, false);
+                    // The connection is still waiting on this response, so it can't be reused
+                    conn.discard();
+                    break 'attempt (RetryOn::Timeout, gateway_timeout());
+                }
+            };
+            balancer.record_outcome(&backend, !resp.status().is_server_error());
+
+            // Dropping a pooled connection returns it to the pool
+            drop(conn);
+
+            let mut resp = resp.map(|b| match timeouts.idle {
+                Some(idle) => IdleTimeout::new(b.map_err(|e| e.into()), idle).boxed(),
+                None => b.map_err(|e| e.into()).boxed(),
+            });
+            let served_by = ServedBy {
+                host: served_host,
+                base_path: backend.addr.base_path().to_string(),
+            };
+            resp.extensions_mut().insert(served_by);
+            if let Some(cookie) = sticky_cookie {
+                resp.extensions_mut().insert(SetStickyCookie(cookie));
+            }
+            let switching = resp.status() == StatusCode::SWITCHING_PROTOCOLS;
+            remove_hop_headers(resp.headers_mut(), upgrading && switching);
+
+            match resp.status() {
+                StatusCode::BAD_GATEWAY => (RetryOn::BadGateway, resp),
+                StatusCode::SERVICE_UNAVAILABLE => (RetryOn::ServiceUnavailable, resp),
+                StatusCode::GATEWAY_TIMEOUT => (RetryOn::GatewayTimeout, resp),
+                _ => return resp,
+            }
+        };
+        tried.push(backend);
+
+        // Requests which reached the server are only retried if they are safe to send again,
+        // and their body could be kept around
+        let retryable = retry.on.contains(&failure)
+            && (idempotent || failure == RetryOn::Connect)
+            && !body.is_spent()
+            && tried.len() < retry.attempts as usize
+            && balancer.retry_budget.try_retry(retry);
+
+        if !retryable {
+            return resp;
+        }
 
-    resp.map(|b| b.map_err(|e| e.into()).boxed())
+        let delay = backoff(retry, tried.len() as u32);
+        cfg_logging! {debug!("Retrying request after {failure:?} in {delay:?}");}
+        tokio::time::sleep(delay).await;
+    }
 }
 
 pub(crate) fn bad_gateway() -> Response<BoxBody<Bytes, crate::Error>> {
@@ -196,16 +361,55 @@ pub(crate) fn bad_gateway() -> Response<BoxBody<Bytes, crate::Error>> {
         .unwrap()
 }
 
+pub(crate) fn gateway_timeout() -> Response<BoxBody<Bytes, crate::Error>> {
+    Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .body(empty())
+        .unwrap()
+}
+
+/// Seconds clients are told to wait before retrying a request rejected by a full upstream queue
+const QUEUE_RETRY_AFTER: HeaderValue = HeaderValue::from_static("1");
+
+/// 503 for requests which couldn't get a connection, see [`crate::config::Pool::max_queued`]
+pub(crate) fn queue_rejected() -> Response<BoxBody<Bytes, crate::Error>> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(RETRY_AFTER, QUEUE_RETRY_AFTER)
+        .body(empty())
+        .unwrap()
+}
+
+pub(crate) fn bad_request() -> Response<BoxBody<Bytes, crate::Error>> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(empty())
+        .unwrap()
+}
+
+pub(crate) fn service_unavailable() -> Response<BoxBody<Bytes, crate::Error>> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(empty())
+        .unwrap()
+}
+
 // TODO: test this, I don't think its correct right now
 /// Returning Ok(None) means no auth needed or auth succeeded
 /// Ok(Some) means respond with this because with failed with the upstream
 pub(crate) async fn authenticate<B>(
     upstreams: &Upstreams,
-    upstream: &UpstreamAndConnPool,
-    peer_addr: SocketAddr,
+    upstream: &UpstreamAndBalancer,
+    client_ip: IpAddr,
+    host: Option<&HeaderValue>,
+    rule: &Rule,
     req: &Request<B>,
 ) -> Result<Option<Response<BoxBody<Bytes, crate::Error>>>, crate::Error> {
-    let Some(authentication) = &upstream.0.authentication else {
+    let Some(authentication) = rule
+        .authentication
+        .as_ref()
+        .or(upstream.0.authentication.as_ref())
+    else {
         return Ok(None);
     };
 
@@ -237,35 +441,68 @@ pub(crate) async fn authenticate<B>(
         auth_req_builder = auth_req_builder.header(k, v);
     }
 
-    let mut auth_req = auth_req_builder.body(Empty::<Bytes>::new()).unwrap();
-    add_proxy_headers(&mut auth_req, &upstream.0, peer_addr);
-    remove_hop_headers(&mut auth_req, false);
-
     let auth_upstream = match &authentication.source {
-        AuthenticationSource::Path(_) => &upstream,
+        AuthenticationSource::Path(_) => upstream,
         AuthenticationSource::Upstream {
             key,
             name: _,
             path: _,
         } => upstreams.get(*key).unwrap(),
     };
+    let Some(auth_backend) = auth_upstream.1.pick(req, client_ip) else {
+        cfg_logging! {error!("No available servers for authentication upstream");}
+        return Ok(Some(service_unavailable()));
+    };
 
-    // TODO: Refactor to use auth upstream's conn pool
-    cfg_logging! {info!("Opened new connection to: {}", upstream.0.addr);}
-    let stream = tcp_connect(auth_upstream.0.addr.authority().unwrap().as_str()).await?;
-    let (mut sender, conn) = client::conn::http1::Builder::new()
-        .preserve_header_case(true)
-        .title_case_headers(true)
-        .handshake::<_, Empty<Bytes>>(TokioIo::new(stream))
-        .await?;
-
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            cfg_logging! {error!("Connection failed: {:?}", err);}
+    let mut auth_req = auth_req_builder.body(empty()).unwrap();
+    set_host(&mut auth_req, host.unwrap_or(&auth_backend.host));
+    *auth_req.uri_mut() = upstream_uri(auth_backend.addr.base_path(), auth_req.uri());
+    remove_hop_headers(auth_req.headers_mut(), false);
+
+    let timeouts = auth_upstream
+        .0
+        .timeouts
+        .overridden_by(rule.timeouts.as_ref());
+    let res = async {
+        let mut conn = auth_backend
+            .pool
+            .get_sender(req.extensions().get::<ClientAddrs>(), timeouts.connect)
+            .await?;
+        conn.ready().await?;
+        match within(timeouts.response, conn.send_request(auth_req)).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                // The connection is still waiting on this response, so it can't be reused
+                conn.discard();
+                Err(crate::Error::Timeout(
+                    "waiting for the authentication response",
+                ))
+            }
         }
-    });
-
-    let res = sender.send_request(auth_req).await?;
+    }
+    .await;
+    // Like proxied requests, so the server's circuit and outlier detection see it
+    let res = match res {
+        Ok(res) => {
+            auth_upstream
+                .1
+                .record_outcome(&auth_backend, !res.status().is_server_error());
+            res
+        }
+        Err(_err @ (crate::Error::QueueFull | crate::Error::QueueTimeout)) => {
+            cfg_logging! {warn!("Rejecting request to authenticate with {}: {_err}", auth_backend);}
+            return Ok(Some(queue_rejected()));
+        }
+        Err(crate::Error::Timeout(_)) => {
+            cfg_logging! {error!("Timed out authenticating with {}", auth_backend);}
+            auth_upstream.1.record_outcome(&auth_backend, false);
+            return Ok(Some(gateway_timeout()));
+        }
+        Err(err) => {
+            auth_upstream.1.record_outcome(&auth_backend, false);
+            return Err(err);
+        }
+    };
 
     if res.status().is_success() {
         Ok(None)
@@ -273,23 +510,3 @@ pub(crate) async fn authenticate<B>(
         Ok(Some(res.map(|b| b.map_err(|e| e.into()).boxed())))
     }
 }
-
-// Create a TCP connection to host:port, build a tunnel between the connection and
-// the upgraded connection
-pub async fn tunnel(upgraded: Upgraded, addr: &str) -> std::io::Result<()> {
-    // Connect to remote server
-    let mut server = tcp_connect(addr).await?;
-    let mut upgraded = TokioIo::new(upgraded);
-
-    // Proxying data
-    let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
-
-    // Print message when done
-    println!(
-        "client wrote {} bytes and received {} bytes",
-        from_client, from_server
-    );
-
-    Ok(())
-}
@@ -0,0 +1,111 @@
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use http_body_util::BodyExt;
+use tower::Service;
+
+use crate::{cfg_logging, handle::util, Body};
+
+type BoxFuture = Pin<Box<dyn Future<Output = Response<Body>> + Send>>;
+
+/// Serves requests in process instead of proxying them, register it in [`Config::handlers`](crate::Config::handlers)
+/// and set a rule's `upstream` to its name.
+///
+/// Requests go through the same rule matching, caching and header changes as proxied ones,
+/// the path the handler gets has the rule's `remove_match` applied
+#[derive(Clone)]
+pub struct Handler(Arc<dyn Fn(Request<Body>) -> BoxFuture + Send + Sync>);
+
+impl Handler {
+    /// Handler calling an async function for each request
+    ///
+    /// ```ignore
+    /// let health = Handler::from_fn(|_req| async { Response::new(Full::new(Bytes::from("ok"))) });
+    /// ```
+    pub fn from_fn<F, Fut, B>(f: F) -> Self
+    where
+        F: Fn(Request<Body>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response<B>> + Send + 'static,
+        B: http_body::Body<Data = Bytes> + Send + Sync + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Self(Arc::new(move |req| {
+            let res = f(req);
+            Box::pin(async move { res.await.map(box_body) })
+        }))
+    }
+
+    /// Handler calling a `tower::Service`, which is cloned for each request.
+    /// Errors from the service are answered with 500 Internal Server Error.
+    ///
+    /// The service gets requests with motorx's [`Body`], not hyper's `Incoming`, since a request
+    /// may come from a [`MotorxService`](crate::MotorxService) or have had its body read already.
+    /// Services which are generic over `http_body::Body` work as is, those written for `Incoming`
+    /// only need the body type changed:
+    ///
+    /// ```ignore
+    /// // Was `service_fn(|req: Request<Incoming>| ...)`
+    /// let echo = tower::service_fn(|req: Request<motorx_core::Body>| async move {
+    ///     let body = req.into_body().collect().await?.to_bytes();
+    ///     Ok::<_, motorx_core::Error>(Response::new(Full::new(body)))
+    /// });
+    /// let handler = Handler::from_service(echo);
+    /// ```
+    pub fn from_service<S, B>(service: S) -> Self
+    where
+        S: Service<Request<Body>, Response = Response<B>> + Clone + Send + Sync + 'static,
+        S::Future: Send,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        B: http_body::Body<Data = Bytes> + Send + Sync + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Self(Arc::new(move |req| {
+            let mut service = service.clone();
+            Box::pin(async move {
+                let res = async {
+                    std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+                    service.call(req).await
+                }
+                .await;
+
+                match res {
+                    Ok(res) => res.map(box_body),
+                    Err(err) => {
+                        let _err: Box<dyn std::error::Error + Send + Sync> = err.into();
+                        cfg_logging! {error!("Handler failed: {_err}");}
+                        Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(util::empty())
+                            .unwrap()
+                    }
+                }
+            })
+        }))
+    }
+
+    pub(crate) async fn call(&self, req: Request<Body>) -> Response<Body> {
+        (self.0)(req).await
+    }
+}
+
+fn box_body<B>(body: B) -> Body
+where
+    B: http_body::Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    body.map_err(|err| crate::Error::Handler(err.into()))
+        .boxed()
+}
+
+impl fmt::Debug for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handler").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Handler {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
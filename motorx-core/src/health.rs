@@ -0,0 +1,324 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{header::HOST, Request};
+use tokio::task::JoinSet;
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    balancer::Backend,
+    cfg_logging,
+    config::{CircuitBreaker, HealthCheck, OutlierDetection},
+    handle::util::empty,
+    Upstreams,
+};
+
+/// Reference point for instants stored in atomics as milliseconds
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+#[inline]
+fn now_millis() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
+/// Health of a backend as seen by active health checks and outlier detection
+#[derive(Debug)]
+pub(crate) struct Health {
+    healthy: AtomicBool,
+    /// Consecutive check results disagreeing with `healthy`
+    streak: AtomicU32,
+    /// Milliseconds since `EPOCH` the backend is ejected until
+    ejected_until: AtomicU64,
+    passive: Mutex<PassiveHealth>,
+}
+
+/// Results of real requests, tracked for outlier detection
+#[derive(Debug, Default)]
+struct PassiveHealth {
+    consecutive_failures: u32,
+    window_start: Option<Instant>,
+    window_requests: u32,
+    window_failures: u32,
+    /// Ejections in a row, used to back off the ejection time
+    times_ejected: u32,
+    last_ejected: Option<Instant>,
+}
+
+impl Health {
+    pub(crate) fn new() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            streak: AtomicU32::new(0),
+            ejected_until: AtomicU64::new(0),
+            passive: Mutex::new(PassiveHealth::default()),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn is_ejected(&self) -> bool {
+        self.ejected_until.load(Ordering::Relaxed) > now_millis()
+    }
+
+    /// Records the result of a real request, returning how long the backend should be ejected for
+    /// if it should be ejected. `can_eject` is false if too many backends are ejected already
+    pub(crate) fn record_outcome(
+        &self,
+        success: bool,
+        detection: &OutlierDetection,
+        can_eject: bool,
+    ) -> Option<Duration> {
+        let mut passive = self.passive.lock().unwrap();
+        let now = Instant::now();
+
+        if passive
+            .window_start
+            .is_none_or(|start| now.duration_since(start) > detection.window)
+        {
+            passive.window_start = Some(now);
+            passive.window_requests = 0;
+            passive.window_failures = 0;
+        }
+
+        passive.window_requests += 1;
+        if success {
+            passive.consecutive_failures = 0;
+            return None;
+        }
+        passive.consecutive_failures += 1;
+        passive.window_failures += 1;
+
+        if !can_eject || self.is_ejected() {
+            return None;
+        }
+
+        let too_many_consecutive = detection
+            .consecutive_failures
+            .is_some_and(|max| passive.consecutive_failures >= max);
+        let error_rate_too_high = detection.error_rate.as_ref().is_some_and(|rate| {
+            passive.window_requests >= rate.min_requests
+                && passive.window_failures * 100 >= rate.percent * passive.window_requests
+        });
+
+        if !too_many_consecutive && !error_rate_too_high {
+            return None;
+        }
+
+        // Forget old ejections once the backend has stayed in rotation for a while
+        if passive
+            .last_ejected
+            .is_some_and(|last| now.duration_since(last) > detection.max_ejection_time * 2)
+        {
+            passive.times_ejected = 0;
+        }
+        passive.times_ejected += 1;
+        passive.last_ejected = Some(now);
+        passive.consecutive_failures = 0;
+        passive.window_start = None;
+
+        let ejection_time = detection
+            .base_ejection_time
+            .saturating_mul(passive.times_ejected)
+            .min(detection.max_ejection_time);
+        self.ejected_until.store(
+            now_millis() + ejection_time.as_millis() as u64,
+            Ordering::Relaxed,
+        );
+
+        Some(ejection_time)
+    }
+
+    /// Records the result of a check, returning the new state if it changed
+    fn record(&self, passed: bool, check: &HealthCheck) -> Option<bool> {
+        if passed == self.is_healthy() {
+            self.streak.store(0, Ordering::Relaxed);
+            return None;
+        }
+
+        let streak = self.streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = if passed { check.rise } else { check.fall };
+
+        if streak >= threshold {
+            self.streak.store(0, Ordering::Relaxed);
+            self.healthy.store(passed, Ordering::Relaxed);
+            Some(passed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Spawns a task for each upstream with a health check, which stops once `upstreams` is dropped
+pub(crate) fn spawn_health_checks(upstreams: &Arc<Upstreams>) {
+    for (key, (upstream, _)) in upstreams.iter().enumerate() {
+        let Some(check) = upstream.health_check.clone() else {
+            continue;
+        };
+        let upstreams = Arc::downgrade(upstreams);
+
+        tokio::spawn(run_health_checks(upstreams, key, Arc::new(check)));
+    }
+}
+
+async fn run_health_checks(upstreams: Weak<Upstreams>, key: usize, check: Arc<HealthCheck>) {
+    let mut interval = tokio::time::interval(check.interval);
+
+    loop {
+        interval.tick().await;
+
+        let Some(backends) = upstreams
+            .upgrade()
+            .map(|upstreams| upstreams[key].1.backends())
+        else {
+            return;
+        };
+
+        let mut checks = JoinSet::new();
+        for backend in backends {
+            let check = Arc::clone(&check);
+            checks.spawn(async move {
+                let passed = check_backend(&backend, &check).await;
+
+                match backend.health.record(passed, &check) {
+                    Some(true) => {
+                        cfg_logging! {info!("Upstream server {} is healthy, adding it back into rotation", backend);}
+                    }
+                    Some(false) => {
+                        cfg_logging! {warn!("Upstream server {} is unhealthy, taking it out of rotation", backend);}
+                    }
+                    None => {}
+                }
+            });
+        }
+        while checks.join_next().await.is_some() {}
+    }
+}
+
+/// Sends a single check request to `backend` on a new connection
+async fn check_backend(backend: &Backend, check: &HealthCheck) -> bool {
+    let result = tokio::time::timeout(check.timeout, async {
+        let mut sender = backend.pool.open(None, None, None).await?;
+
+        let req = Request::builder()
+            .uri(&check.path)
+            .header(HOST, &backend.host)
+            .header("user-agent", "motorx-health-check")
+            .body(empty())
+            .unwrap();
+
+        Ok::<_, crate::Error>(sender.send_request(req).await?.status())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(status)) => match check.expected_status {
+            Some(expected) => status == expected,
+            None => status.is_success(),
+        },
+        Ok(Err(_err)) => {
+            cfg_logging! {debug!("Health check to {} failed: {_err}", backend);}
+            false
+        }
+        Err(_) => {
+            cfg_logging! {debug!("Health check to {} timed out", backend);}
+            false
+        }
+    }
+}
+
+/// Per upstream circuit breaker, see [`CircuitBreaker`]
+#[derive(Debug)]
+pub(crate) struct Circuit {
+    config: CircuitBreaker,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug)]
+enum CircuitState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// Waiting on a trial request, another is let through if it hasn't reported back by the instant
+    HalfOpen {
+        trial_expires: Instant,
+    },
+}
+
+impl Circuit {
+    pub(crate) fn new(config: CircuitBreaker) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a request should be let through
+    pub(crate) fn allows(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until }
+            | CircuitState::HalfOpen {
+                trial_expires: until,
+            } if now < until => false,
+            CircuitState::Open { .. } => {
+                cfg_logging! {info!("Circuit half-open, letting a trial request through");}
+                *state = CircuitState::HalfOpen {
+                    trial_expires: now + self.config.open_duration,
+                };
+                true
+            }
+            // Trial request never reported back, let another one through
+            CircuitState::HalfOpen { .. } => {
+                *state = CircuitState::HalfOpen {
+                    trial_expires: now + self.config.open_duration,
+                };
+                true
+            }
+        }
+    }
+
+    pub(crate) fn record_outcome(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        match (&mut *state, success) {
+            (CircuitState::Closed { failures }, true) => *failures = 0,
+            (CircuitState::Closed { failures }, false) => {
+                *failures += 1;
+                if *failures >= self.config.failure_threshold {
+                    cfg_logging! {warn!("Circuit opened after {} consecutive failures", failures);}
+                    *state = CircuitState::Open {
+                        until: Instant::now() + self.config.open_duration,
+                    };
+                }
+            }
+            (CircuitState::HalfOpen { .. }, true) => {
+                cfg_logging! {info!("Trial request succeeded, circuit closed");}
+                *state = CircuitState::Closed { failures: 0 };
+            }
+            (CircuitState::HalfOpen { .. }, false) => {
+                cfg_logging! {warn!("Trial request failed, circuit opened");}
+                *state = CircuitState::Open {
+                    until: Instant::now() + self.config.open_duration,
+                };
+            }
+            // Requests let through before the circuit opened
+            (CircuitState::Open { .. }, _) => {}
+        }
+    }
+}
@@ -14,45 +14,64 @@
 //! }
 //! ```
 
+mod balancer;
 pub mod config;
 mod conn_pool;
+mod connector;
+mod dns;
 pub mod error;
+mod forwarded;
 mod handle;
+mod handler;
+mod health;
 #[macro_use]
 pub mod log;
 mod cache;
 #[cfg(test)]
 mod e2e;
 mod listener;
+pub mod metrics;
+mod proxy_protocol;
+mod request_id;
+mod retry;
+pub mod service;
+mod timeout;
 #[cfg(feature = "tls")]
 pub mod tls;
 
-#[cfg_attr(feature = "logging", macro_use(info, error, debug, trace))]
+#[cfg_attr(feature = "logging", macro_use(info, warn, error, debug, trace))]
 #[cfg(feature = "logging")]
 extern crate tracing;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use balancer::Balancer;
 use cache::Cache;
 use config::Upstream;
-use conn_pool::ConnPool;
+use dns::Resolver;
 use hyper::body::Incoming;
 use hyper::service::service_fn;
 use hyper::Request;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use listener::Listener;
+use metrics::Metrics;
+use proxy_protocol::ClientAddrs;
 #[cfg(feature = "tls")]
 use tls::stream::TlsStream;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::Service;
 
 pub use config::{CacheSettings, Config, Rule};
 pub use error::Error;
+pub use handler::Handler;
+pub use service::{Body, MotorxService};
 
-// TODO: Consider Boxing this (Or just ConnPool) to improve spacial locality
-type UpstreamAndConnPool = (Arc<Upstream>, ConnPool);
-type Upstreams = Vec<UpstreamAndConnPool>;
+// TODO: Consider Boxing this (Or just Balancer) to improve spacial locality
+type UpstreamAndBalancer = (Arc<Upstream>, Balancer);
+type Upstreams = Vec<UpstreamAndBalancer>;
+type CommonConfig = (Arc<Config>, Arc<Cache>, Arc<Upstreams>);
 
 /// Motorx proxy server
 ///
@@ -69,37 +88,21 @@ type Upstreams = Vec<UpstreamAndConnPool>;
 /// }
 /// ```
 pub struct Server {
-    config: Arc<Config>,
-    cache: Arc<Cache>,
-    upstreams: Arc<Upstreams>,
+    service: MotorxService,
     listener: Listener,
     /// Used to enforce max num of connections to this server
     semaphore: Arc<Semaphore>,
 }
 
 impl Server {
-    /// Do configuration shared between raw and tls servers
-    fn common_config(
-        mut config: Config,
-    ) -> Result<(Arc<Config>, Arc<Cache>, Arc<Upstreams>, Listener), Error> {
-        let upstreams = Arc::new(init_upstreams(&mut config));
-        let cache = Arc::new(Cache::from_config(&mut config));
-
-        config.rules.sort_by(|a, b| a.path.cmp(&b.path));
-        let config = Arc::new(config);
-
-        cfg_logging! {debug!("Starting with config: {:#?}", *config);}
-
-        Ok((
-            config.clone(),
-            cache,
-            upstreams,
-            Listener::from_config(&config)?,
-        ))
+    pub fn new(config: Config) -> Result<Self, Error> {
+        Self::from_service(MotorxService::new(config)?)
     }
 
-    pub fn new(config: Config) -> Result<Self, Error> {
-        let (config, cache, conn_pools, listener) = Self::common_config(config)?;
+    /// Server for `service`, ex. one built with layers, listening on its config's `addr`
+    pub fn from_service(service: MotorxService) -> Result<Self, Error> {
+        let config = &service.shared().config;
+        let listener = Listener::from_config(config)?;
 
         cfg_logging! {
             info!("Motorx proxy listening on http://{}", {
@@ -109,9 +112,7 @@ impl Server {
 
         Ok(Self {
             semaphore: Arc::new(Semaphore::new(config.max_connections)),
-            cache,
-            upstreams: conn_pools,
-            config,
+            service,
             listener,
         })
     }
@@ -120,21 +121,24 @@ impl Server {
         self.listener.local_addr()
     }
 
+    /// Handle for reading the upstreams' queue depth and traffic while the server runs
+    pub fn metrics(&self) -> Metrics {
+        self.service.metrics()
+    }
+
     pub async fn run(mut self) -> Result<(), hyper::Error> {
         loop {
             if let Ok(permit) = self.semaphore.clone().acquire_owned().await {
                 match self.listener.accept().await {
-                    Ok((stream, peer_addr)) => {
+                    Ok((stream, peer, local)) => {
                         cfg_logging! {
-                            trace!("Accepted connection from {}", peer_addr);
+                            trace!("Accepted connection from {}", peer);
                         }
 
                         handle_connection(
                             stream,
-                            peer_addr,
-                            Arc::clone(&self.config),
-                            Arc::clone(&self.cache),
-                            Arc::clone(&self.upstreams),
+                            ClientAddrs { peer, local },
+                            self.service.clone(),
                             permit,
                         );
                     }
@@ -151,30 +155,22 @@ impl Server {
 
 #[cfg_attr(
     feature = "logging",
-    tracing::instrument(skip(stream, config, cache, permit))
+    tracing::instrument(skip(stream, service, permit))
 )]
 fn handle_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
     stream: S,
-    peer_addr: SocketAddr,
-    config: Arc<Config>,
-    cache: Arc<Cache>,
-    conn_pools: Arc<Upstreams>,
+    client: ClientAddrs,
+    service: MotorxService,
     permit: OwnedSemaphorePermit,
 ) {
-    let service = service_fn(move |req: Request<Incoming>| {
-        let config = config.clone();
-        let cache = cache.clone();
-        let conn_pools = conn_pools.clone();
+    let peer_addr = client.peer;
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        // Kept with the request so upstream connections can say who the client is
+        req.extensions_mut().insert(client);
+        let res = service.clone().call(req);
 
         async move {
-            let res = handle::handle_req(
-                req,
-                peer_addr,
-                Arc::clone(&config),
-                Arc::clone(&cache),
-                Arc::clone(&conn_pools),
-            )
-            .await;
+            let res = res.await;
 
             cfg_logging! {
                 trace!("Responded to req from {}", peer_addr);
@@ -218,7 +214,79 @@ async fn tcp_connect(
     tokio::net::TcpStream::connect(addr).await
 }
 
-fn init_upstreams(config: &mut Config) -> Upstreams {
+/// Sets up the parts of `config` used while serving requests, and starts its background tasks
+fn init(mut config: Config) -> Result<CommonConfig, Error> {
+    let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~:".contains(c);
+    if config.proxy_name.is_empty() || !config.proxy_name.chars().all(is_token) {
+        return Err(Error::Config(format!(
+            "Invalid proxy_name {:?}, it may only contain letters, digits, and !#$%&'*+-.^_`|~:",
+            config.proxy_name
+        )));
+    }
+
+    let groups = std::mem::take(&mut config.groups);
+    config.rules.extend(config::group::compile(&groups)?);
+
+    if let Some(name) = config
+        .handlers
+        .keys()
+        .find(|name| config.upstreams.contains_key(*name))
+    {
+        return Err(Error::Config(format!(
+            "{name:?} is the name of both an upstream and a handler"
+        )));
+    }
+    for rule in &mut config.rules {
+        rule.handler = config.handlers.get(&rule.upstream).cloned();
+    }
+
+    for (name, upstream) in &config.upstreams {
+        upstream
+            .request_headers
+            .check_request(format_args!("Upstream {name:?}"))?;
+    }
+    // Requests to handlers keep the `Host` they were sent with
+    for rule in config.rules.iter().filter(|rule| rule.handler.is_none()) {
+        rule.request_headers
+            .check_request(format_args!("The rule for {}", rule.path))?;
+    }
+    for rule in &config.rules {
+        match rule.authentication.as_ref().map(|auth| &auth.source) {
+            Some(_) if rule.handler.is_some() => {
+                return Err(Error::Config(format!(
+                    "The rule for {} is served by a handler, which can't have `authentication`",
+                    rule.path
+                )));
+            }
+            Some(config::authentication::AuthenticationSource::Upstream { name, .. })
+                if !config.upstreams.contains_key(name) =>
+            {
+                return Err(Error::Config(format!(
+                    "The rule for {} authenticates with {name:?}, which isn't an upstream",
+                    rule.path
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    let resolver = Resolver::new(std::mem::take(&mut config.hosts));
+    let upstreams = Arc::new(init_upstreams(&mut config, &resolver)?);
+    health::spawn_health_checks(&upstreams);
+    dns::spawn_resolvers(&upstreams, &resolver);
+    conn_pool::spawn_pool_maintenance(&upstreams);
+    balancer::spawn_dynamic_expiry(&upstreams);
+    let cache = Arc::new(Cache::from_config(&mut config));
+
+    config.rules.sort_by(|a, b| a.path.cmp(&b.path));
+    let config = Arc::new(config);
+
+    cfg_logging! {debug!("Starting with config: {:#?}", *config);}
+
+    Ok((config, cache, upstreams))
+}
+
+fn init_upstreams(config: &mut Config, resolver: &Resolver) -> Result<Upstreams, Error> {
     let mut upstreams = Vec::with_capacity(config.upstreams.len());
 
     let mut upstream_order = Vec::new();
@@ -229,7 +297,7 @@ fn init_upstreams(config: &mut Config) -> Upstreams {
 
     for (key, upstream_name) in upstream_order.iter().enumerate() {
         // Find any authentication referencing this upstream and populate their key
-        for (_, upstream) in &mut config.upstreams {
+        for upstream in config.upstreams.values_mut() {
             if let Some(auth) = Arc::get_mut(upstream).unwrap().authentication.as_mut() {
                 match &mut auth.source {
                     config::authentication::AuthenticationSource::Upstream {
@@ -247,6 +315,16 @@ fn init_upstreams(config: &mut Config) -> Upstreams {
             if rule.upstream == *upstream_name {
                 rule.upstream_key = key;
             }
+            if let Some(config::authentication::AuthenticationSource::Upstream {
+                name,
+                key: upstream_key,
+                ..
+            }) = rule.authentication.as_mut().map(|auth| &mut auth.source)
+            {
+                if name == upstream_name {
+                    *upstream_key = key;
+                }
+            }
         }
     }
 
@@ -256,11 +334,11 @@ fn init_upstreams(config: &mut Config) -> Upstreams {
         Arc::get_mut(upstream).unwrap().key = key;
         upstreams.push((
             Arc::clone(upstream),
-            ConnPool::new(upstream.addr.clone(), upstream.max_connections),
+            Balancer::from_upstream(upstream, resolver)?,
         ));
     }
 
     upstreams.shrink_to_fit();
 
-    upstreams
+    Ok(upstreams)
 }
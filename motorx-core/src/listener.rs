@@ -7,25 +7,25 @@ use tokio::{
 
 use crate::{config::Tls, Config};
 
+#[cfg(feature = "tls")]
+type AcmeIncoming = rustls_acme::tokio::TokioIncoming<
+    tokio_util::compat::Compat<TcpStream>,
+    io::Error,
+    rustls_acme::tokio::TokioIncomingTcpWrapper<
+        TcpStream,
+        io::Error,
+        tokio_stream::wrappers::TcpListenerStream,
+    >,
+    io::Error,
+    io::Error,
+>;
+
 pub(crate) enum Listener {
     Plain(tokio::net::TcpListener),
     #[cfg(feature = "tls")]
     FileTls(tokio::net::TcpListener, Arc<rustls::ServerConfig>),
     #[cfg(feature = "tls")]
-    AcmeTls(
-        rustls_acme::tokio::TokioIncoming<
-            tokio_util::compat::Compat<TcpStream>,
-            io::Error,
-            rustls_acme::tokio::TokioIncomingTcpWrapper<
-                TcpStream,
-                io::Error,
-                tokio_stream::wrappers::TcpListenerStream,
-            >,
-            io::Error,
-            io::Error,
-        >,
-        SocketAddr,
-    ),
+    AcmeTls(Box<AcmeIncoming>, SocketAddr),
 }
 
 pub(crate) enum Stream {
@@ -60,9 +60,7 @@ impl Listener {
                             // Load private key.
                             let key = tls::load_private_key(private_key).unwrap();
 
-                            rustls::crypto::ring::default_provider()
-                                .install_default()
-                                .unwrap();
+                            tls::install_crypto_provider();
 
                             // Do not use client certificate authentication.
                             let mut cfg = rustls::ServerConfig::builder()
@@ -90,7 +88,7 @@ impl Listener {
                                 vec![b"h2".to_vec(), b"http/1.1".to_vec()],
                             );
 
-                        Ok(Self::AcmeTls(tls_incoming, local_addr))
+                        Ok(Self::AcmeTls(Box::new(tls_incoming), local_addr))
                     }
                 }
             }
@@ -112,18 +110,21 @@ impl Listener {
         }
     }
 
-    pub(crate) async fn accept(&mut self) -> io::Result<(Stream, SocketAddr)> {
+    /// Accepts the next client, along with its address and the local address it connected to
+    pub(crate) async fn accept(&mut self) -> io::Result<(Stream, SocketAddr, SocketAddr)> {
         match self {
-            Listener::Plain(tcp_listener) => tcp_listener
-                .accept()
-                .await
-                .map(|(s, peer)| (Stream::Plain(s), peer)),
+            Listener::Plain(tcp_listener) => {
+                let (tcp_stream, peer) = tcp_listener.accept().await?;
+                let local = tcp_stream.local_addr()?;
+                Ok((Stream::Plain(tcp_stream), peer, local))
+            }
             #[cfg(feature = "tls")]
             Listener::FileTls(tcp_listener, server_config) => {
                 let (tcp_stream, peer) = tcp_listener.accept().await?;
+                let local = tcp_stream.local_addr()?;
                 let tls_stream =
                     crate::tls::stream::TlsStream::new(tcp_stream, server_config.clone());
-                Ok((Stream::FileTls(tls_stream), peer))
+                Ok((Stream::FileTls(tls_stream), peer, local))
             }
             #[cfg(feature = "tls")]
             Listener::AcmeTls(tokio_incoming, _) => {
@@ -135,8 +136,9 @@ impl Listener {
                     .await
                     .expect("Listener closed unexpectedly")?
                     .into_inner();
-                let peer = stream.get_ref().0.get_ref().peer_addr()?;
-                Ok((Stream::AcmeTls(stream.compat()), peer))
+                let tcp_stream = stream.get_ref().0.get_ref();
+                let (peer, local) = (tcp_stream.peer_addr()?, tcp_stream.local_addr()?);
+                Ok((Stream::AcmeTls(stream.compat()), peer, local))
             }
         }
     }
@@ -0,0 +1,52 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{Config, Upstreams};
+
+/// Reads live numbers about a [`Server`](crate::Server)'s upstreams, get one with [`Server::metrics`](crate::Server::metrics)
+/// or [`MotorxService::metrics`](crate::MotorxService::metrics). It stays usable after the server starts running
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    config: Arc<Config>,
+    upstreams: Arc<Upstreams>,
+}
+
+/// Numbers for a single upstream at the time it was read
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamMetrics {
+    /// Requests waiting for a connection because every connection is busy
+    pub queued: usize,
+    /// Requests waiting for or using a connection
+    pub in_flight: usize,
+    /// Requests rejected with 503 since startup, for finding the queue full or waiting too long
+    pub rejected: u64,
+}
+
+impl Metrics {
+    pub(crate) fn new(config: Arc<Config>, upstreams: Arc<Upstreams>) -> Self {
+        Self { config, upstreams }
+    }
+
+    /// Current numbers for each upstream, by the name it has in the config
+    pub fn upstreams(&self) -> HashMap<String, UpstreamMetrics> {
+        self.config
+            .upstreams
+            .iter()
+            .map(|(name, upstream)| {
+                let balancer = &self.upstreams[upstream.key].1;
+
+                (
+                    name.clone(),
+                    UpstreamMetrics {
+                        queued: balancer.queue.waiting(),
+                        in_flight: balancer
+                            .backends()
+                            .iter()
+                            .map(|backend| backend.pool.in_flight())
+                            .sum(),
+                        rejected: balancer.queue.rejected(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
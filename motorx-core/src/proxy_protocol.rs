@@ -0,0 +1,112 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::ProxyProtocol;
+
+/// Signature every v2 header starts with
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Addresses of the client connection a request came in on, stored in the request's extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ClientAddrs {
+    pub(crate) peer: SocketAddr,
+    /// Address of the proxy the client connected to
+    pub(crate) local: SocketAddr,
+}
+
+impl ClientAddrs {
+    /// These addresses with `client` as the peer, ex. the client found through `trusted_proxies`.
+    /// The port is only known for the peer, it is 0 for other clients
+    pub(crate) fn for_client(self, client: IpAddr) -> Self {
+        if client == self.peer.ip() {
+            return self;
+        }
+
+        Self {
+            peer: SocketAddr::new(client, 0),
+            local: self.local,
+        }
+    }
+}
+
+/// PROXY protocol header to send at the start of a connection opened for `client`.
+/// Connections not opened for a client (ex. health checks) say so with `UNKNOWN` or `LOCAL`
+pub(crate) fn header(version: ProxyProtocol, client: Option<&ClientAddrs>) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => v1(client).into_bytes(),
+        ProxyProtocol::V2 => v2(client),
+    }
+}
+
+fn v1(client: Option<&ClientAddrs>) -> String {
+    let Some(client) = client else {
+        return "PROXY UNKNOWN\r\n".into();
+    };
+
+    let (peer, local) = same_family(client);
+    let family = if peer.is_ipv4() { "TCP4" } else { "TCP6" };
+
+    format!(
+        "PROXY {family} {} {} {} {}\r\n",
+        peer.ip(),
+        local.ip(),
+        peer.port(),
+        local.port()
+    )
+}
+
+fn v2(client: Option<&ClientAddrs>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+
+    let Some(client) = client else {
+        // Version 2, LOCAL command, unspecified family with no addresses
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        return header;
+    };
+
+    // Version 2, PROXY command
+    header.push(0x21);
+
+    match same_family(client) {
+        (SocketAddr::V4(peer), SocketAddr::V4(local)) => {
+            // TCP over ipv4, 12 bytes of addresses
+            header.extend_from_slice(&[0x11, 0, 12]);
+            header.extend_from_slice(&peer.ip().octets());
+            header.extend_from_slice(&local.ip().octets());
+        }
+        (peer, local) => {
+            // TCP over ipv6, 36 bytes of addresses
+            header.extend_from_slice(&[0x21, 0, 36]);
+            header.extend_from_slice(&ipv6_octets(peer.ip()));
+            header.extend_from_slice(&ipv6_octets(local.ip()));
+        }
+    }
+    header.extend_from_slice(&client.peer.port().to_be_bytes());
+    header.extend_from_slice(&client.local.port().to_be_bytes());
+
+    header
+}
+
+/// Both addresses in one family, ipv4 addresses are mapped to ipv6 if the other is ipv6
+fn same_family(client: &ClientAddrs) -> (SocketAddr, SocketAddr) {
+    if client.peer.is_ipv4() == client.local.is_ipv4() {
+        return (client.peer, client.local);
+    }
+
+    (
+        SocketAddr::new(IpAddr::V6(ipv6(client.peer.ip())), client.peer.port()),
+        SocketAddr::new(IpAddr::V6(ipv6(client.local.ip())), client.local.port()),
+    )
+}
+
+#[inline]
+fn ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[inline]
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    ipv6(ip).octets()
+}
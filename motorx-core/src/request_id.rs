@@ -0,0 +1,61 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::{HeaderName, HeaderValue, Request};
+
+/// Longest id accepted from a client, longer ones are replaced
+const MAX_ID_LEN: usize = 128;
+
+/// Id of a request, stored in the request's extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestId(pub(crate) HeaderValue);
+
+/// Reuses the id the client sent in `header`, or generates one if it is missing or unusable.
+/// The id is put in `header` so upstreams get it too
+pub(crate) fn assign<B>(req: &mut Request<B>, header: &HeaderName) -> HeaderValue {
+    let id = req
+        .headers()
+        .get(header)
+        .filter(|id| is_usable(id))
+        .cloned()
+        .unwrap_or_else(generate);
+
+    req.headers_mut().insert(header.clone(), id.clone());
+    req.extensions_mut().insert(RequestId(id.clone()));
+    id
+}
+
+fn is_usable(id: &HeaderValue) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.as_bytes().iter().all(|byte| byte.is_ascii_graphic())
+}
+
+/// UUIDv7, so ids sort by when requests came in
+fn generate() -> HeaderValue {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let mut bytes = fastrand::u128(..).to_be_bytes();
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    // version 7
+    bytes[6] = (bytes[6] & 0x0f) | 0x70;
+    // RFC 9562 variant
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    let id = format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    );
+
+    HeaderValue::from_str(&id).expect("uuids are valid header values")
+}
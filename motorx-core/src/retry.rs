@@ -0,0 +1,137 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::Method;
+use once_cell::sync::Lazy;
+
+use crate::{
+    config::{Retry, RetryOn},
+    conn_pool::ProxyBody,
+    handle::util::full,
+};
+
+/// Largest body which is buffered so the request can be retried after it was sent
+pub(crate) const MAX_RETRY_BODY: u64 = 64 * 1024;
+
+/// Used for rules without a retry policy, retries failing to connect once
+pub(crate) static DEFAULT_RETRY: Lazy<Retry> = Lazy::new(|| Retry {
+    attempts: 2,
+    on: vec![RetryOn::Connect],
+    backoff: Duration::ZERO,
+    ..Default::default()
+});
+
+/// Counts requests and retries to an upstream over the last second, see [`Retry::budget_percent`]
+#[derive(Debug)]
+pub(crate) struct RetryBudget {
+    window: Mutex<Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    pub(crate) fn new() -> Self {
+        Self {
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn record_request(&self) {
+        self.with_window(|window| window.requests += 1);
+    }
+
+    /// Takes a retry out of the budget, `false` if there is none left
+    pub(crate) fn try_retry(&self, retry: &Retry) -> bool {
+        self.with_window(|window| {
+            let allowed = u64::from(retry.min_retries_per_second)
+                + window.requests * u64::from(retry.budget_percent) / 100;
+
+            if window.retries < allowed {
+                window.retries += 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    fn with_window<T>(&self, f: impl FnOnce(&mut Window) -> T) -> T {
+        let mut window = self.window.lock().unwrap();
+
+        if window.started.elapsed() >= Duration::from_secs(1) {
+            *window = Window {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+
+        f(&mut window)
+    }
+}
+
+/// Delay before retry number `retry` (starting at 1), exponential with full jitter
+pub(crate) fn backoff(policy: &Retry, retry: u32) -> Duration {
+    let max = policy
+        .backoff
+        .saturating_mul(2u32.saturating_pow(retry - 1))
+        .min(policy.max_backoff);
+    max.mul_f64(fastrand::f64())
+}
+
+/// Whether sending a request with `method` more than once has the same effect as sending it once
+#[inline]
+pub(crate) fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Whether `policy` retries failures which happen after the request was sent,
+/// only these need the body to be sent again
+pub(crate) fn retries_after_sending(policy: &Retry) -> bool {
+    policy.on.iter().any(|on| *on != RetryOn::Connect)
+}
+
+/// Body of a request which may be sent more than once
+pub(crate) enum RetryBody {
+    /// Small body read ahead of time, sent as many times as needed
+    Buffered(Bytes),
+    /// Body which can only be sent once, it is given back if the request never left
+    Streaming(Option<ProxyBody>),
+}
+
+impl RetryBody {
+    /// Body for the next attempt, `None` if the body was already sent
+    pub(crate) fn take(&mut self) -> Option<ProxyBody> {
+        match self {
+            RetryBody::Buffered(bytes) => Some(full(bytes.clone())),
+            RetryBody::Streaming(body) => body.take(),
+        }
+    }
+
+    /// Whether the body can't be sent again
+    pub(crate) fn is_spent(&self) -> bool {
+        matches!(self, RetryBody::Streaming(None))
+    }
+
+    /// Gives back a body from [`RetryBody::take`] which wasn't sent
+    pub(crate) fn put_back(&mut self, unsent: ProxyBody) {
+        if let RetryBody::Streaming(body) = self {
+            *body = Some(unsent);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use http::{HeaderValue, Request, Response};
+use http_body::{Frame, SizeHint};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use sync_wrapper::SyncWrapper;
+use tower::{util::BoxCloneSyncService, Layer, Service, ServiceExt};
+
+use crate::{
+    cache::Cache,
+    config::{Retry, Rule, Timeouts},
+    handle::{self, util},
+    metrics::Metrics,
+    proxy_protocol::ClientAddrs,
+    request_id, Config, UpstreamAndBalancer, Upstreams,
+};
+
+/// Body of the requests and responses going through motorx
+pub type Body = BoxBody<Bytes, crate::Error>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type BoxService = BoxCloneSyncService<Request<Body>, Response<Body>, crate::Error>;
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Motorx's routing and proxying as a `tower::Service`, to compose it with tower middleware
+/// or mount it in another server (ex. axum). [`Server`](crate::Server) serves connections with one.
+///
+/// The client's address is read from the request's `SocketAddr` extension, `0.0.0.0` is used without one.
+/// Health checks, dns resolution and other background tasks are spawned on the tokio runtime it is built in
+#[derive(Clone)]
+pub struct MotorxService {
+    shared: Arc<Shared>,
+    /// Routing with the layers around it, `None` if there are none
+    routes: Option<BoxService>,
+}
+
+pub(crate) struct Shared {
+    pub(crate) config: Arc<Config>,
+    pub(crate) cache: Arc<Cache>,
+    pub(crate) upstreams: Arc<Upstreams>,
+    /// Upstream calls with the layers around them, `None` if there are none
+    upstream_calls: Option<BoxService>,
+}
+
+impl MotorxService {
+    pub fn new(config: Config) -> Result<Self, crate::Error> {
+        Self::builder(config).build()
+    }
+
+    /// Builder for adding layers around routing and upstream calls
+    pub fn builder(config: Config) -> Builder {
+        Builder {
+            config,
+            route_layers: Vec::new(),
+            upstream_layers: Vec::new(),
+        }
+    }
+
+    /// Handle for reading the upstreams' queue depth and traffic while the service is used
+    pub fn metrics(&self) -> Metrics {
+        Metrics::new(
+            Arc::clone(&self.shared.config),
+            Arc::clone(&self.shared.upstreams),
+        )
+    }
+
+    pub(crate) fn shared(&self) -> &Arc<Shared> {
+        &self.shared
+    }
+}
+
+type LayerFn = Box<dyn FnOnce(BoxService) -> BoxService + Send>;
+
+/// Builds a [`MotorxService`], with `tower::Layer`s around parts of it.
+/// Layers added later wrap the ones added before them
+pub struct Builder {
+    config: Config,
+    route_layers: Vec<LayerFn>,
+    upstream_layers: Vec<LayerFn>,
+}
+
+impl Builder {
+    /// Adds a layer around routing, which gets every request before it is matched to a rule.
+    /// Errors from the layer are returned from the service
+    pub fn route_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<BoxService> + Send + 'static,
+        L::Service:
+            Service<Request<Body>, Response = Response<Body>> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request<Body>>>::Error: Into<BoxError>,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        self.route_layers
+            .push(Box::new(move |service| boxed(layer.layer(service))));
+        self
+    }
+
+    /// Adds a layer around each request proxied to an upstream, a call includes its retries.
+    /// Errors from the layer are answered with 502 Bad Gateway.
+    ///
+    /// Upgrades and requests to authenticate don't go through these layers
+    pub fn upstream_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<BoxService> + Send + 'static,
+        L::Service:
+            Service<Request<Body>, Response = Response<Body>> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request<Body>>>::Error: Into<BoxError>,
+        <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+    {
+        self.upstream_layers
+            .push(Box::new(move |service| boxed(layer.layer(service))));
+        self
+    }
+
+    pub fn build(self) -> Result<MotorxService, crate::Error> {
+        let (config, cache, upstreams) = crate::init(self.config)?;
+
+        let upstream_calls = apply(self.upstream_layers, || BoxCloneSyncService::new(Proxy));
+        let shared = Arc::new(Shared {
+            config,
+            cache,
+            upstreams,
+            upstream_calls,
+        });
+        let routes = apply(self.route_layers, || {
+            BoxCloneSyncService::new(Router {
+                shared: Arc::clone(&shared),
+            })
+        });
+
+        Ok(MotorxService { shared, routes })
+    }
+}
+
+/// `layers` around the service `inner` makes, `None` if there aren't any
+fn apply(layers: Vec<LayerFn>, inner: impl FnOnce() -> BoxService) -> Option<BoxService> {
+    if layers.is_empty() {
+        return None;
+    }
+
+    Some(
+        layers
+            .into_iter()
+            .fold(inner(), |service, layer| layer(service)),
+    )
+}
+
+fn boxed<S>(service: S) -> BoxService
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    BoxCloneSyncService::new(service.map_err(|err: S::Error| crate::Error::Layer(err.into())))
+}
+
+impl<B> Service<Request<B>> for MotorxService
+where
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = crate::Error;
+    type Future = BoxFuture<Result<Response<Body>, crate::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut req = req.map(SyncBody::boxed);
+        let request_id = self
+            .shared
+            .config
+            .request_id_header
+            .as_ref()
+            .map(|header| (header.clone(), request_id::assign(&mut req, header)));
+        let shared = Arc::clone(&self.shared);
+        let routes = self.routes.clone();
+
+        Box::pin(async move {
+            let mut res = match routes {
+                Some(routes) => routes.oneshot(req).await,
+                None => route(req, shared).await,
+            };
+
+            if let (Ok(res), Some((header, id))) = (&mut res, request_id) {
+                res.headers_mut().insert(header, id);
+            }
+
+            res
+        })
+    }
+}
+
+/// Matches requests to rules and sends them where the rule says
+#[derive(Clone)]
+struct Router {
+    shared: Arc<Shared>,
+}
+
+impl Service<Request<Body>> for Router {
+    type Response = Response<Body>;
+    type Error = crate::Error;
+    type Future = BoxFuture<Result<Response<Body>, crate::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        Box::pin(route(req, Arc::clone(&self.shared)))
+    }
+}
+
+async fn route(req: Request<Body>, shared: Arc<Shared>) -> Result<Response<Body>, crate::Error> {
+    let extensions = req.extensions();
+    let peer_addr = extensions
+        .get::<ClientAddrs>()
+        .map(|client| client.peer)
+        .or_else(|| extensions.get::<SocketAddr>().copied())
+        .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+
+    handle::handle_req(req, peer_addr, shared).await
+}
+
+/// What `Proxy` needs to send a request to an upstream, in the request's extensions
+#[derive(Clone)]
+struct UpstreamCall {
+    upstreams: Arc<Upstreams>,
+    key: usize,
+    client_ip: IpAddr,
+    host: Option<HeaderValue>,
+    retry: Option<Retry>,
+    timeouts: Option<Timeouts>,
+}
+
+/// Sends requests to the upstream in their `UpstreamCall`
+#[derive(Clone)]
+struct Proxy;
+
+impl Service<Request<Body>> for Proxy {
+    type Response = Response<Body>;
+    type Error = crate::Error;
+    type Future = BoxFuture<Result<Response<Body>, crate::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let call = req
+            .extensions_mut()
+            .remove::<UpstreamCall>()
+            .expect("upstream calls are made with an `UpstreamCall`");
+
+        Box::pin(async move {
+            Ok(util::proxy_request(
+                req,
+                &call.upstreams[call.key],
+                call.client_ip,
+                call.host.as_ref(),
+                false,
+                call.retry.as_ref(),
+                call.timeouts.as_ref(),
+            )
+            .await)
+        })
+    }
+}
+
+/// Proxies `req` to `upstream` for `rule`, through the upstream layers if there are any
+pub(crate) async fn call_upstream(
+    shared: &Shared,
+    mut req: Request<Body>,
+    upstream: &UpstreamAndBalancer,
+    client_ip: IpAddr,
+    host: Option<&HeaderValue>,
+    rule: &Rule,
+) -> Response<Body> {
+    let Some(upstream_calls) = &shared.upstream_calls else {
+        return util::proxy_request(
+            req,
+            upstream,
+            client_ip,
+            host,
+            false,
+            rule.retry.as_ref(),
+            rule.timeouts.as_ref(),
+        )
+        .await;
+    };
+
+    req.extensions_mut().insert(UpstreamCall {
+        upstreams: Arc::clone(&shared.upstreams),
+        key: upstream.0.key,
+        client_ip,
+        host: host.cloned(),
+        retry: rule.retry.clone(),
+        timeouts: rule.timeouts,
+    });
+
+    match upstream_calls.clone().oneshot(req).await {
+        Ok(res) => res,
+        Err(_err) => {
+            cfg_logging! {error!("Upstream call failed: {_err}");}
+            util::bad_gateway()
+        }
+    }
+}
+
+/// Request body made `Sync` by only using it through `&mut`,
+/// which lets any `Send` body (ex. axum's) go through motorx
+struct SyncBody<B> {
+    body: SyncWrapper<Pin<Box<B>>>,
+    size_hint: SizeHint,
+    is_end_stream: bool,
+}
+
+impl<B> SyncBody<B>
+where
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    fn boxed(body: B) -> Body {
+        SyncBody {
+            size_hint: body.size_hint(),
+            is_end_stream: body.is_end_stream(),
+            body: SyncWrapper::new(Box::pin(body)),
+        }
+        .boxed()
+    }
+}
+
+impl<B> http_body::Body for SyncBody<B>
+where
+    B: http_body::Body,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = crate::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        match ready!(this.body.get_mut().as_mut().poll_frame(cx)) {
+            Some(Ok(frame)) => {
+                let frame = frame.map_data(|mut data| data.copy_to_bytes(data.remaining()));
+                if let Some(data) = frame.data_ref() {
+                    // Keep the hint of what's left accurate
+                    let read = data.len() as u64;
+                    let mut size_hint = SizeHint::new();
+                    if let Some(upper) = this.size_hint.upper() {
+                        size_hint.set_upper(upper.saturating_sub(read));
+                    }
+                    size_hint.set_lower(this.size_hint.lower().saturating_sub(read));
+                    this.size_hint = size_hint;
+                }
+
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Some(Err(err)) => Poll::Ready(Some(Err(crate::Error::Body(err.into())))),
+            None => {
+                this.is_end_stream = true;
+                this.size_hint = SizeHint::with_exact(0);
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.is_end_stream
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.size_hint.clone()
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use tokio::time::{error::Elapsed, Instant, Sleep};
+
+/// Runs `fut` to completion, or until `limit` passes if there is one
+pub(crate) async fn within<F: Future>(
+    limit: Option<Duration>,
+    fut: F,
+) -> Result<F::Output, Elapsed> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, fut).await,
+        None => Ok(fut.await),
+    }
+}
+
+/// Response body which errors once the upstream goes `idle` without sending another frame
+pub(crate) struct IdleTimeout<B> {
+    body: B,
+    idle: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<B> IdleTimeout<B> {
+    pub(crate) fn new(body: B, idle: Duration) -> Self {
+        Self {
+            body,
+            idle,
+            sleep: Box::pin(tokio::time::sleep(idle)),
+        }
+    }
+}
+
+impl<B> Body for IdleTimeout<B>
+where
+    B: Body<Data = Bytes, Error = crate::Error> + Unpin,
+{
+    type Data = Bytes;
+    type Error = crate::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        match Pin::new(&mut this.body).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                let deadline = Instant::now() + this.idle;
+                this.sleep.as_mut().reset(deadline);
+                Poll::Ready(frame)
+            }
+            Poll::Pending => {
+                ready!(this.sleep.as_mut().poll(cx));
+                Poll::Ready(Some(Err(crate::Error::Timeout(
+                    "waiting for the upstream's response body",
+                ))))
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Response body which errors once `deadline` passes before it is finished
+pub(crate) struct Deadline<B> {
+    body: B,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<B> Deadline<B> {
+    pub(crate) fn new(body: B, deadline: Instant) -> Self {
+        Self {
+            body,
+            sleep: Box::pin(tokio::time::sleep_until(deadline)),
+        }
+    }
+}
+
+impl<B> Body for Deadline<B>
+where
+    B: Body<Data = Bytes, Error = crate::Error> + Unpin,
+{
+    type Data = Bytes;
+    type Error = crate::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        // Checked first, so a body which is always ready can't run past it
+        if !this.body.is_end_stream() && this.sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Err(crate::Error::Timeout(
+                "waiting for the upstream's whole response",
+            ))));
+        }
+
+        Pin::new(&mut this.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
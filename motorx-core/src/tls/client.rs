@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::config::UpstreamTls;
+
+/// Builds the rustls config used to connect to an upstream's servers
+pub(crate) fn client_config(tls: &UpstreamTls) -> Result<ClientConfig, crate::Error> {
+    super::install_crypto_provider();
+
+    let builder = ClientConfig::builder();
+    let builder = if tls.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier))
+    } else {
+        let mut roots = RootCertStore::empty();
+
+        if let Some(ca_file) = &tls.ca_file {
+            for cert in super::load_certs(ca_file)? {
+                roots.add(cert)?;
+            }
+        }
+
+        if tls.system_roots {
+            let native = rustls_native_certs::load_native_certs();
+            #[cfg(feature = "logging")]
+            for err in &native.errors {
+                warn!("Failed to load a system certificate: {err}");
+            }
+            roots.add_parsable_certificates(native.certs);
+
+            if roots.is_empty() {
+                // No system certificates found, fall back to Mozilla's
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+        }
+
+        let verifier = WebPkiServerVerifier::builder(Arc::new(roots))
+            .build()
+            .map_err(|e| crate::Error::Config(format!("Invalid upstream tls roots: {e}")))?;
+
+        match &tls.verify_hostname {
+            Some(name) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(OverrideNameVerifier {
+                    inner: verifier,
+                    name: server_name(name)?,
+                })),
+            None => builder.with_webpki_verifier(verifier),
+        }
+    };
+
+    let config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(super::load_certs(cert)?, super::load_private_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(crate::Error::Config(
+                "`client_cert` and `client_key` must be set together".into(),
+            ))
+        }
+    };
+
+    Ok(config)
+}
+
+pub(crate) fn server_name(name: &str) -> Result<ServerName<'static>, crate::Error> {
+    ServerName::try_from(name.to_owned())
+        .map_err(|_| crate::Error::Config(format!("Invalid tls server name: {name}")))
+}
+
+/// Verifies certificates against a fixed name, instead of the name sent with SNI
+#[derive(Debug)]
+struct OverrideNameVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for OverrideNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, &self.name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Accepts any certificate, signatures are still checked so the handshake is sound
+#[derive(Debug)]
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -3,6 +3,7 @@ use std::{fs, io, path::Path};
 use itertools::Itertools;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
+pub(crate) mod client;
 pub mod stream;
 
 // Load public certificate from file.
@@ -23,7 +24,7 @@ pub(crate) fn load_certs(filename: impl AsRef<Path>) -> io::Result<Vec<Certifica
         .try_collect::<_, Vec<_>, _>()
         .map_err(|e| error(e.to_string()))?;
 
-    if certs.len() < 1 {
+    if certs.is_empty() {
         return Err(error("Cannot have empty certs.".into()));
     }
 
@@ -51,6 +52,14 @@ pub(crate) fn load_private_key(filename: impl AsRef<Path>) -> io::Result<Private
     Ok(key)
 }
 
+/// Installs ring as the process-wide crypto provider, if one isn't installed already.
+pub(crate) fn install_crypto_provider() {
+    // Fails if a provider was already installed, which is fine
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+}
+
 fn error(err: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
+    io::Error::other(err)
 }